@@ -22,9 +22,10 @@ fn main() -> anyhow::Result<()> {
 
     let package_manager = PackageManager::new(args.packages_path, args.version, args.platform)?;
 
-    for tag in &package_manager.named_tags {
-        let activity_pkg = &package_manager.package_paths[&tag.hash.pkg_id()];
-        let activity_pkg = &activity_pkg.filename;
+    for tag in &package_manager.lookup.read().named_tags {
+        let activity_pkg = package_manager
+            .path_for_tag(tag.hash)
+            .map_or_else(|| "<unknown package>".to_string(), |p| p.filename);
 
         println!(
             "{activity_pkg}: {} - {} (D2Class_{:08x})",
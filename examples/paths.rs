@@ -22,7 +22,7 @@ fn main() -> anyhow::Result<()> {
 
     let package_manager = PackageManager::new(args.packages_path, args.version, args.platform)?;
 
-    for (p, path) in &package_manager.package_paths {
+    for (p, path) in package_manager.packages_sorted() {
         println!("{p:04x}: {path:?}",);
     }
 
@@ -1,8 +1,5 @@
-use std::collections::HashMap;
-
 use clap::Parser;
 use destiny_pkg::{package::PackagePlatform, GameVersion, PackageManager};
-use rustc_hash::FxHashMap;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None, disable_version_flag(true))]
@@ -23,33 +20,17 @@ fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
     let package_manager = PackageManager::new(args.packages_path, args.version, args.platform)?;
-    let mut totals: HashMap<(u8, u8), (usize, usize)> = Default::default();
-    let mut references: FxHashMap<u32, (usize, usize)> = Default::default();
-
-    for (_, entries) in package_manager.package_entry_index {
-        for entry in entries {
-            if entry.file_type == 8 || entry.file_type == 16 {
-                let e = references.entry(entry.reference).or_default();
-                e.0 += 1;
-                e.1 += entry.file_size as usize;
-            }
-
-            let e = totals
-                .entry((entry.file_type, entry.file_subtype))
-                .or_default();
-            e.0 += entry.file_size as usize;
-            e.1 += 1;
-        }
-    }
+    let references = package_manager.reference_histogram(None);
 
-    let mut resorted_totals: Vec<((u8, u8), (usize, usize))> = totals.into_iter().collect();
-    resorted_totals.sort_by_key(|((t, s), _)| ((*t as u32) << 16) | *s as u32);
-    for ((ftype, fsubtype), (size, count)) in resorted_totals {
+    for stat in package_manager.type_statistics() {
+        let size = stat.total_bytes as usize;
         println!(
-            "{ftype}.{fsubtype} - {} ({} files, {} per file on average)",
+            "{}.{} - {} ({} files, {} per file on average)",
+            stat.file_type,
+            stat.file_subtype,
             format_file_size(size),
-            split_thousands(count, '\''),
-            format_file_size(size / count)
+            split_thousands(stat.count, '\''),
+            format_file_size(size / stat.count)
         );
     }
 
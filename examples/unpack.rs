@@ -1,8 +1,12 @@
-use std::{fs::File, io::Write, path::PathBuf};
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+};
 
 use clap::Parser;
 use clap_num::maybe_hex;
-use destiny_pkg::{package::classify_file_prebl, GameVersion, TagHash};
+use destiny_pkg::{package::classify_file, GameVersion, TagHash};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None, disable_version_flag(true))]
@@ -82,11 +86,7 @@ fn main() -> anyhow::Result<()> {
         }
         let ref_hash = TagHash(e.reference);
 
-        let ext = if args.version == GameVersion::Destiny2Shadowkeep {
-            classify_file_prebl(e.file_type, e.file_subtype)
-        } else {
-            "bin".to_string()
-        };
+        let ext = classify_file(args.version, e.file_type, e.file_subtype);
 
         if !args.silent {
             if ref_hash.is_pkg_file() {
@@ -103,23 +103,23 @@ fn main() -> anyhow::Result<()> {
         }
 
         if !args.dry_run {
-            let data: Vec<u8> = match package.read_entry(i) {
-                Ok(data) => data,
-                Err(e) => {
-                    eprintln!(
-                        "Failed to extract entry {}/{}: {e}",
-                        i,
-                        package.entries().len() - 1
-                    );
-                    continue;
-                }
-            };
-
-            let mut o = File::create(format!(
+            let out_path = format!(
                 "{out_dir}/{i}_{:08x}_t{}_s{}.{ext}",
                 e.reference, e.file_type, e.file_subtype
-            ))?;
-            o.write_all(&data)?;
+            );
+            let mut o = BufWriter::new(File::create(&out_path)?);
+            if let Err(e) = package
+                .copy_entry_to(i, &mut o)
+                .and_then(|_| Ok(o.flush()?))
+            {
+                eprintln!(
+                    "Failed to extract entry {}/{}: {e}",
+                    i,
+                    package.entries().len() - 1
+                );
+                drop(o);
+                std::fs::remove_file(&out_path).ok();
+            }
         }
     }
 
@@ -1,9 +1,9 @@
-use std::{fs::File, io::Write};
+use std::{fs::File, io::Write, path::Path};
 
 use clap::Parser;
 use clap_num::maybe_hex;
 use destiny_pkg::{
-    package::{classify_file_prebl, PackagePlatform},
+    package::{classify_file, PackagePlatform},
     GameVersion, PackageManager, TagHash,
 };
 
@@ -20,9 +20,9 @@ struct Args {
     #[arg(short, long, default_value = "false")]
     dry_run: bool,
 
-    /// Directory to extract to (default: ./out/pkg_name)
-    #[arg(short)]
-    output_dir: Option<String>,
+    /// Directory to extract to
+    #[arg(short, default_value = "./out/")]
+    output_dir: String,
 
     /// Version of the package to extract
     #[arg(short, value_enum)]
@@ -38,32 +38,23 @@ fn main() -> anyhow::Result<()> {
     let package_manager = PackageManager::new(args.packages_path, args.version, args.platform)?;
 
     for (t, e) in package_manager.get_all_by_reference(args.reference) {
-        let pkg_path = package_manager.package_paths.get(&t.pkg_id()).unwrap();
-        let pkg_name = &pkg_path.filename;
+        let ext = classify_file(args.version, e.file_type, e.file_subtype);
+        let out_path = Path::new(&args.output_dir).join(package_manager.suggested_path(t, &ext));
 
-        let out_dir = args
-            .output_dir
-            .clone()
-            .unwrap_or_else(|| format!("./out/{pkg_name}"));
-
-        let ext = if args.version == GameVersion::Destiny2Shadowkeep {
-            classify_file_prebl(e.file_type, e.file_subtype)
-        } else {
-            "bin".to_string()
-        };
-
-        std::fs::create_dir_all(&out_dir).ok();
         let ref_hash = TagHash(e.reference);
         if ref_hash.is_pkg_file() {
             println!(
-                "{:04x}/{} 0x{:04x} - Reference {ref_hash:?} / r=0x{:x} (type={}, subtype={}, ext={ext})",
-                t.pkg_id(), t.entry_index(), e.file_size, ref_hash.0, e.file_type, e.file_subtype
+                "{} 0x{:04x} - Reference {ref_hash:?} / r=0x{:x} (type={}, subtype={}, ext={ext})",
+                t.to_pkg_entry_string(),
+                e.file_size,
+                ref_hash.0,
+                e.file_type,
+                e.file_subtype
             );
         } else {
             println!(
-                "{:04x}/{} 0x{:04x} - r=0x{:x} (type={}, subtype={}, ext={ext})",
-                t.pkg_id(),
-                t.entry_index(),
+                "{} 0x{:04x} - r=0x{:x} (type={}, subtype={}, ext={ext})",
+                t.to_pkg_entry_string(),
                 e.file_size,
                 ref_hash.0,
                 e.file_type,
@@ -75,22 +66,16 @@ fn main() -> anyhow::Result<()> {
             let data = match package_manager.read_tag(t) {
                 Ok(data) => data,
                 Err(e) => {
-                    eprintln!(
-                        "Failed to extract entry {:04x}/{}: {e}",
-                        t.pkg_id(),
-                        t.entry_index()
-                    );
+                    eprintln!("Failed to extract entry {}: {e}", t.to_pkg_entry_string());
                     continue;
                 }
             };
 
-            let mut o = File::create(format!(
-                "{out_dir}/{}_{:08x}_t{}_s{}.{ext}",
-                t.entry_index(),
-                e.reference,
-                e.file_type,
-                e.file_subtype
-            ))?;
+            if let Some(parent) = out_path.parent() {
+                std::fs::create_dir_all(parent).ok();
+            }
+
+            let mut o = File::create(&out_path)?;
             o.write_all(&data)?;
         }
     }
@@ -1,8 +1,8 @@
-use std::{fs::File, io::Write};
+use std::{fs::File, io::Write, path::Path};
 
 use clap::Parser;
 use destiny_pkg::{
-    package::{classify_file_prebl, PackagePlatform},
+    package::{classify_file, PackagePlatform},
     GameVersion, PackageManager, TagHash,
 };
 
@@ -39,29 +39,28 @@ fn main() -> anyhow::Result<()> {
             eprintln!("Tag {} does not exist!", tag);
             continue;
         };
-        let pkg_path = package_manager.package_paths.get(&tag.pkg_id()).unwrap();
+        let Some(pkg_path) = package_manager.path_for_tag(tag) else {
+            eprintln!("Package for tag {} does not exist!", tag);
+            continue;
+        };
         let pkg_name = &pkg_path.filename;
 
-        let out_dir = args.output_dir.clone();
-
-        let ext = if args.version == GameVersion::Destiny2Shadowkeep {
-            classify_file_prebl(entry.file_type, entry.file_subtype)
-        } else {
-            "bin".to_string()
-        };
+        let ext = classify_file(args.version, entry.file_type, entry.file_subtype);
+        let out_path = Path::new(&args.output_dir).join(package_manager.suggested_path(tag, &ext));
+        if let Some(parent) = out_path.parent() {
+            std::fs::create_dir_all(parent).ok();
+        }
 
-        std::fs::create_dir_all(&out_dir).ok();
         let ref_hash = TagHash(entry.reference);
         if ref_hash.is_pkg_file() {
             println!(
-                "{pkg_name} {:04x}/{} 0x{:04x} - Reference {ref_hash:?} / r=0x{:x} (type={}, subtype={}, ext={ext})",
-                tag.pkg_id(), tag.entry_index(), entry.file_size, ref_hash.0, entry.file_type, entry.file_subtype
+                "{pkg_name} {} 0x{:04x} - Reference {ref_hash:?} / r=0x{:x} (type={}, subtype={}, ext={ext})",
+                tag.to_pkg_entry_string(), entry.file_size, ref_hash.0, entry.file_type, entry.file_subtype
             );
         } else {
             println!(
-                "{pkg_name} {:04x}/{} 0x{:04x} - r=0x{:x} (type={}, subtype={}, ext={ext})",
-                tag.pkg_id(),
-                tag.entry_index(),
+                "{pkg_name} {} 0x{:04x} - r=0x{:x} (type={}, subtype={}, ext={ext})",
+                tag.to_pkg_entry_string(),
                 entry.file_size,
                 ref_hash.0,
                 entry.file_type,
@@ -72,20 +71,13 @@ fn main() -> anyhow::Result<()> {
         let data = match package_manager.read_tag(tag) {
             Ok(data) => data,
             Err(e) => {
-                eprintln!(
-                    "Failed to extract entry {:04x}/{}: {e}",
-                    tag.pkg_id(),
-                    tag.entry_index()
-                );
+                eprintln!("Failed to extract entry {}: {e}", tag.to_pkg_entry_string());
 
                 continue;
             }
         };
 
-        let mut o = File::create(format!(
-            "{out_dir}/{tag}_ref-{:08X}_{}_{}.{ext}",
-            entry.reference, entry.file_type, entry.file_subtype
-        ))?;
+        let mut o = File::create(&out_path)?;
         o.write_all(&data)?;
     }
 
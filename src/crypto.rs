@@ -1,8 +1,16 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Formatter},
+    sync::Arc,
+};
 
-use aes_gcm::{aead::AeadMutInPlace, Aes128Gcm, KeyInit};
+use aes_gcm::{
+    aead::{AeadInPlace, AeadMutInPlace},
+    Aes128Gcm, KeyInit,
+};
 use itertools::Itertools;
 use lazy_static::lazy_static;
+use parking_lot::RwLock;
 use tracing::{error, info};
 
 use crate::GameVersion;
@@ -26,6 +34,37 @@ lazy_static! {
     };
 }
 
+/// Group key along with the IV it's used with
+type GroupCipher = (Aes128Gcm, [u8; 12]);
+
+/// Keys for package groups that only apply to the packages opened by one [PackageManager](crate::PackageManager).
+/// Cloning shares the underlying storage, so keys registered later are visible to every clone
+#[derive(Clone, Default)]
+pub struct PackageKeys(Arc<RwLock<HashMap<u64, GroupCipher>>>);
+
+impl PackageKeys {
+    pub fn insert(&self, group: u64, key: [u8; 16], iv: [u8; 12]) {
+        self.0
+            .write()
+            .insert(group, (Aes128Gcm::new(&key.into()), iv));
+    }
+
+    pub fn contains(&self, group: u64) -> bool {
+        self.0.read().contains_key(&group)
+    }
+
+    fn get(&self, group: u64) -> Option<GroupCipher> {
+        self.0.read().get(&group).cloned()
+    }
+}
+
+impl Debug for PackageKeys {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        // Never print the keys themselves
+        write!(f, "PackageKeys({} groups)", self.0.read().len())
+    }
+}
+
 pub struct PkgGcmState {
     nonce: [u8; 12],
     cipher_0: Aes128Gcm,
@@ -50,36 +89,35 @@ impl PkgGcmState {
     ];
 
     pub fn new(pkg_id: u16, version: GameVersion, group: u64) -> PkgGcmState {
-        let mut g = PkgGcmState {
-            nonce: Self::AES_NONCE_BASE,
+        Self::with_keys(pkg_id, version, group, &PackageKeys::default())
+    }
+
+    /// Same as [PkgGcmState::new], but looks up the group key in `keys` before falling back to the global keys
+    pub fn with_keys(
+        pkg_id: u16,
+        version: GameVersion,
+        group: u64,
+        keys: &PackageKeys,
+    ) -> PkgGcmState {
+        PkgGcmState {
+            nonce: derive_nonce(version, pkg_id),
             cipher_0: Aes128Gcm::new(&Self::AES_KEY_0.into()),
             cipher_1: Aes128Gcm::new(&Self::AES_KEY_1.into()),
-            cipher_extra: CIPHERS_EXTRA.get(&group).cloned(),
+            cipher_extra: keys
+                .get(group)
+                .or_else(|| CIPHERS_EXTRA.get(&group).cloned()),
             group,
-        };
-
-        g.shift_nonce(pkg_id, version);
-
-        g
-    }
-
-    fn shift_nonce(&mut self, pkg_id: u16, version: GameVersion) {
-        self.nonce[0] ^= (pkg_id >> 8) as u8;
-        match version {
-            GameVersion::Destiny2Beta | GameVersion::Destiny2Shadowkeep => self.nonce[1] = 0xf9,
-            _ => self.nonce[1] = 0xea,
         }
-        self.nonce[11] ^= pkg_id as u8;
     }
 
     pub fn decrypt_block_in_place(
-        &mut self,
+        &self,
         flags: u16,
         tag: &[u8],
         data: &mut [u8],
     ) -> anyhow::Result<()> {
         if (flags & 0x8) != 0 {
-            if let Some((cipher, iv)) = self.cipher_extra.as_mut() {
+            if let Some((cipher, iv)) = self.cipher_extra.as_ref() {
                 match cipher.decrypt_in_place_detached(iv.as_slice().into(), &[], data, tag.into())
                 {
                     Ok(_) => {
@@ -96,9 +134,9 @@ impl PkgGcmState {
         }
 
         let (cipher, nonce) = if (flags & 0x4) != 0 {
-            (&mut self.cipher_1, &self.nonce)
+            (&self.cipher_1, &self.nonce)
         } else {
-            (&mut self.cipher_0, &self.nonce)
+            (&self.cipher_0, &self.nonce)
         };
 
         match cipher.decrypt_in_place_detached(nonce.into(), &[], data, tag.into()) {
@@ -108,6 +146,76 @@ impl PkgGcmState {
     }
 }
 
+/// Nonce used for blocks encrypted with the built-in keys (flag 0x8 unset) in the given package.
+/// Blocks encrypted with a group key use the IV from that key instead
+pub fn derive_nonce(version: GameVersion, pkg_id: u16) -> [u8; 12] {
+    let mut nonce = PkgGcmState::AES_NONCE_BASE;
+    nonce[0] ^= (pkg_id >> 8) as u8;
+    nonce[1] = match version {
+        GameVersion::Destiny2Beta | GameVersion::Destiny2Shadowkeep => 0xf9,
+        _ => 0xea,
+    };
+    nonce[11] ^= pkg_id as u8;
+
+    nonce
+}
+
+/// Decrypts a single block in place, for blocks read outside of a [Package](crate::Package) such as through
+/// [PackageManager::read_raw_block](crate::PackageManager::read_raw_block). `flags` and `gcm_tag` come from the block
+/// header and `group` from the package header. Group keys are looked up in `keys` before the global keys, pass
+/// [PackageManager::keys](crate::PackageManager::keys) to use the keys registered with a manager
+pub fn try_decrypt_block(
+    version: GameVersion,
+    pkg_id: u16,
+    group: u64,
+    keys: &PackageKeys,
+    flags: u16,
+    gcm_tag: &[u8; 16],
+    data: &mut [u8],
+) -> anyhow::Result<()> {
+    PkgGcmState::with_keys(pkg_id, version, group, keys)
+        .decrypt_block_in_place(flags, gcm_tag, data)
+}
+
+/// Whether an external key is registered for the given package group
+pub fn has_key_for_group(group: u64) -> bool {
+    CIPHERS_EXTRA.contains_key(&group)
+}
+
+impl PkgGcmState {
+    /// Whether an external key is registered for this package's group
+    pub fn has_extra_key(&self) -> bool {
+        self.cipher_extra.is_some()
+    }
+
+    /// Encrypts a block in place using the key selected by `flags`, returning the GCM tag
+    pub fn encrypt_block_in_place(
+        &mut self,
+        flags: u16,
+        data: &mut [u8],
+    ) -> anyhow::Result<[u8; 16]> {
+        let tag = if (flags & 0x8) != 0 {
+            let Some((cipher, iv)) = self.cipher_extra.as_mut() else {
+                return Err(anyhow::anyhow!(format!(
+                    "No key found for PKG group {:016X}",
+                    self.group
+                )));
+            };
+
+            cipher.encrypt_in_place_detached(iv.as_slice().into(), &[], data)
+        } else if (flags & 0x4) != 0 {
+            self.cipher_1
+                .encrypt_in_place_detached(self.nonce.as_slice().into(), &[], data)
+        } else {
+            self.cipher_0
+                .encrypt_in_place_detached(self.nonce.as_slice().into(), &[], data)
+        }
+        .map_err(|_| anyhow::anyhow!("Failed to encrypt PKG data block"))?;
+
+        Ok(tag.into())
+    }
+}
+
 // example key `123456789ABCDEF:ABCDA1B2C3D4E5F6A7B8C9D0E1F2A3B4C5D:1234567890ABCDEF // optional comment`
 pub fn parse_keys(data: &str) -> Vec<(u64, [u8; 16], [u8; 12])> {
     data.lines()
@@ -171,3 +279,79 @@ pub fn parse_keys(data: &str) -> Vec<(u64, [u8; 16], [u8; 12])> {
         })
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derive_nonce_vectors() {
+        let cases = [
+            (
+                GameVersion::Destiny2Shadowkeep,
+                0x0123,
+                [
+                    0x85, 0xf9, 0x11, 0xc0, 0xac, 0xab, 0xfa, 0x20, 0x33, 0x11, 0x26, 0xba,
+                ],
+            ),
+            (
+                GameVersion::Destiny2WitchQueen,
+                0x0123,
+                [
+                    0x85, 0xea, 0x11, 0xc0, 0xac, 0xab, 0xfa, 0x20, 0x33, 0x11, 0x26, 0xba,
+                ],
+            ),
+            (
+                GameVersion::Destiny2Beta,
+                0xffff,
+                [
+                    0x7b, 0xf9, 0x11, 0xc0, 0xac, 0xab, 0xfa, 0x20, 0x33, 0x11, 0x26, 0x66,
+                ],
+            ),
+            (
+                GameVersion::Destiny2Episodes,
+                0x0000,
+                [
+                    0x84, 0xea, 0x11, 0xc0, 0xac, 0xab, 0xfa, 0x20, 0x33, 0x11, 0x26, 0x99,
+                ],
+            ),
+        ];
+
+        for (version, pkg_id, expected) in cases {
+            assert_eq!(
+                derive_nonce(version, pkg_id),
+                expected,
+                "{version:?} {pkg_id:04x}"
+            );
+        }
+    }
+
+    #[test]
+    fn decrypt_block_with_scoped_keys() {
+        let version = GameVersion::Destiny2WitchQueen;
+        let group = 0x1122334455667788;
+        let keys = PackageKeys::default();
+        keys.insert(group, [0x42; 16], [0x24; 12]);
+
+        let flags = 0x2 | 0x8;
+        let mut data = b"block data".to_vec();
+        let tag = PkgGcmState::with_keys(0x123, version, group, &keys)
+            .encrypt_block_in_place(flags, &mut data)
+            .unwrap();
+
+        let mut copy = data.clone();
+        assert!(try_decrypt_block(
+            version,
+            0x123,
+            group,
+            &PackageKeys::default(),
+            flags,
+            &tag,
+            &mut copy
+        )
+        .is_err());
+
+        try_decrypt_block(version, 0x123, group, &keys, flags, &tag, &mut data).unwrap();
+        assert_eq!(data, b"block data");
+    }
+}
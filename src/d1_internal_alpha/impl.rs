@@ -1,49 +1,53 @@
 use std::{
-    collections::hash_map::Entry,
-    fs::File,
-    io::{BufReader, Read, Seek, SeekFrom},
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
-    },
+    io::{Read, Seek, SeekFrom},
+    sync::Arc,
 };
 
 use anyhow::Context;
 use binrw::{BinReaderExt, Endian, VecArgs};
 use parking_lot::RwLock;
-use rustc_hash::FxHashMap;
+use tracing::debug;
 
 use crate::{
     d1_internal_alpha::structs::{BlockHeader, EntryHeader, EntryHeader2, PackageHeader},
     d1_roi::structs::NamedTagEntryD1,
-    oodle,
-    package::{Package, ReadSeek, UEntryHeader, UHashTableEntry, BLOCK_CACHE_SIZE},
+    d2_shared::BLOCK_SIZE,
+    oodle::{self, OodleVersion},
+    package::{
+        package_path_base, BlockCache, BlockInfo, Package, PackageOpenOptions, ReadSeek,
+        UEntryHeader, UHashTableEntry,
+    },
+    tag::{PatchId, PkgId},
     PackageNamedTagEntry,
 };
 
-pub const BLOCK_SIZE: usize = 0x40000;
-
 pub struct PackageD1InternalAlpha {
     pub header: PackageHeader,
     entries: Vec<EntryHeader>,
+    /// Secondary entry table, see [EntryHeader2]
     entries2: Vec<EntryHeader2>,
     unified_entries: Vec<UEntryHeader>,
     blocks: Vec<BlockHeader>,
     named_tags: Vec<PackageNamedTagEntry>,
+    /// Dev package headers don't store the file size, so we take it from the stream instead
+    file_size: u64,
 
     reader: RwLock<Box<dyn ReadSeek>>,
     path_base: String,
 
-    block_counter: AtomicUsize,
-    block_cache: RwLock<FxHashMap<usize, (usize, Arc<Vec<u8>>)>>,
+    block_cache: BlockCache,
 }
 
 unsafe impl Send for PackageD1InternalAlpha {}
 unsafe impl Sync for PackageD1InternalAlpha {}
 
 impl PackageD1InternalAlpha {
-    pub fn open(path: &str) -> anyhow::Result<PackageD1InternalAlpha> {
-        let reader = BufReader::new(File::open(path)?);
+    /// Dev packages don't use patch files, so only the [source](PackageOpenOptions::source) of the options is used
+    pub fn open(
+        path: &str,
+        options: &PackageOpenOptions,
+    ) -> anyhow::Result<PackageD1InternalAlpha> {
+        let reader = options.open_file(path)?;
 
         Self::from_reader(path, reader)
     }
@@ -83,8 +87,9 @@ impl PackageD1InternalAlpha {
                 .finalize(),
         )?;
 
-        let last_underscore_pos = path.rfind('_').unwrap();
-        let path_base = path[..last_underscore_pos].to_owned();
+        let file_size = reader.seek(SeekFrom::End(0))?;
+
+        let path_base = package_path_base(path)?;
 
         let unified_entries = entries
             .iter()
@@ -95,10 +100,21 @@ impl PackageD1InternalAlpha {
                 starting_block: e.starting_block,
                 starting_block_offset: e.starting_block_offset,
                 file_size: e.file_size,
+                raw_type_info: e.raw_type_info,
+                raw_block_info: e.raw_block_info,
             })
             .collect();
 
-        // assert_eq!(entries.len(), entries2.len());
+        // The secondary table doesn't line up with the entry table in every package, and nothing in it is needed to
+        // locate or classify entries, so it's only kept around for inspection
+        if entries.len() != entries2.len() {
+            debug!(
+                "Package {:04x} has {} entries but {} secondary entries",
+                header.pkg_id,
+                entries.len(),
+                entries2.len()
+            );
+        }
 
         Ok(PackageD1InternalAlpha {
             path_base,
@@ -108,22 +124,23 @@ impl PackageD1InternalAlpha {
             entries2,
             unified_entries,
             blocks,
-            block_counter: AtomicUsize::default(),
+            file_size,
             block_cache: Default::default(),
             // Remap named tags to D2 struct for convenience
             named_tags: named_tags
                 .into_iter()
-                .map(|n: NamedTagEntryD1| PackageNamedTagEntry {
-                    hash: n.hash,
-                    class_hash: n.class_hash,
-                    name: String::from_utf8_lossy(&n.name).into_owned(),
+                .map(|n: NamedTagEntryD1| {
+                    PackageNamedTagEntry::from_fixed_name(n.hash, n.class_hash, &n.name)
                 })
                 .collect(),
         })
     }
 
     fn get_block_raw(&self, block_index: usize) -> anyhow::Result<Vec<u8>> {
-        let bh = &self.blocks[block_index];
+        let bh = self
+            .blocks
+            .get(block_index)
+            .context("Block index out of bounds")?;
         let mut data = vec![0u8; bh.size as usize];
 
         // cohae: Dev packages dont make use of patch ids, they're always 0, so just read from the current file
@@ -143,9 +160,7 @@ impl PackageD1InternalAlpha {
         let block_data = self.get_block_raw(block_index)?.to_vec();
 
         Ok(if (bh.flags & 0x1) != 0 {
-            let mut buffer = vec![0u8; BLOCK_SIZE];
-            let _decompressed_size = oodle::decompress_3(&block_data, &mut buffer)?;
-            buffer
+            oodle::decompress_block(OodleVersion::V3, block_index, &block_data, BLOCK_SIZE)?
         } else {
             block_data
         })
@@ -157,19 +172,27 @@ impl Package for PackageD1InternalAlpha {
         Endian::Big
     }
 
-    fn pkg_id(&self) -> u16 {
-        self.header.pkg_id
+    fn pkg_id(&self) -> PkgId {
+        PkgId(self.header.pkg_id)
     }
 
-    fn patch_id(&self) -> u16 {
+    fn patch_id(&self) -> PatchId {
         // Dev packages do not use patch numbers
-        0
+        PatchId(0)
+    }
+
+    fn format_version(&self) -> u16 {
+        (self.header.magic >> 16) as u16
     }
 
     fn hash64_table(&self) -> Vec<UHashTableEntry> {
         vec![]
     }
 
+    fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
     fn named_tags(&self) -> Vec<PackageNamedTagEntry> {
         self.named_tags.clone()
     }
@@ -182,37 +205,39 @@ impl Package for PackageD1InternalAlpha {
         self.unified_entries.get(index).cloned()
     }
 
+    fn block_info(&self, index: usize) -> Option<BlockInfo> {
+        self.blocks.get(index).map(|b| BlockInfo {
+            index,
+            patch_id: b.patch_id,
+            flags: b.flags,
+            size: b.size,
+            compressed: (b.flags & 0x1) != 0,
+        })
+    }
+
+    fn get_block_raw_bytes(&self, block_index: usize) -> anyhow::Result<Vec<u8>> {
+        self.get_block_raw(block_index)
+    }
+
+    fn read_block_uncached(&self, block_index: usize) -> anyhow::Result<Vec<u8>> {
+        self.read_block(block_index)
+    }
+
+    fn block_cache_bytes(&self) -> usize {
+        self.block_cache.size_bytes()
+    }
+
     fn get_block(&self, block_index: usize) -> anyhow::Result<Arc<Vec<u8>>> {
-        let (_, b) = match self.block_cache.write().entry(block_index) {
-            Entry::Occupied(o) => o.get().clone(),
-            Entry::Vacant(v) => {
-                let block = self.read_block(*v.key())?;
-                let b = v
-                    .insert((self.block_counter.load(Ordering::Relaxed), Arc::new(block)))
-                    .clone();
-
-                self.block_counter.store(
-                    self.block_counter.load(Ordering::Relaxed) + 1,
-                    Ordering::Relaxed,
-                );
-
-                b
-            }
-        };
-
-        while self.block_cache.read().len() > BLOCK_CACHE_SIZE {
-            let bc = self.block_cache.read();
-            let (oldest, _) = bc
-                .iter()
-                .min_by(|(_, (at, _)), (_, (bt, _))| at.cmp(bt))
-                .unwrap();
-
-            let oldest = *oldest;
-            drop(bc);
-
-            self.block_cache.write().remove(&oldest);
-        }
+        self.block_cache
+            .get_or_read(block_index, |i| self.read_block(i))
+    }
 
-        Ok(b)
+    fn with_block(
+        &self,
+        block_index: usize,
+        f: &mut dyn FnMut(&[u8]) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        self.block_cache
+            .with_block(block_index, |i| self.read_block(i), f)
     }
 }
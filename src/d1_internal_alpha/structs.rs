@@ -50,24 +50,27 @@ pub struct PackageHeader {
 pub struct EntryHeader {
     pub reference: u32,
 
-    _thing: u32,
-    #[br(calc = (_thing >> 18) as u8)]
+    pub raw_type_info: u32,
+    #[br(calc = (raw_type_info >> 18) as u8)]
     pub file_type: u8,
-    #[br(calc = (_thing & 0xff) as u8)]
+    #[br(calc = (raw_type_info & 0xff) as u8)]
     pub file_subtype: u8,
 
-    _block_info: u64,
+    pub raw_block_info: u64,
 
-    #[br(calc = _block_info as u32 & 0x3fff)]
+    #[br(calc = raw_block_info as u32 & 0x3fff)]
     pub starting_block: u32,
 
-    #[br(calc = ((_block_info >> 14) as u32 & 0x3FFF) << 4)]
+    #[br(calc = ((raw_block_info >> 14) as u32 & 0x3FFF) << 4)]
     pub starting_block_offset: u32,
 
-    #[br(calc = (_block_info >> 28) as u32 & 0x3FFFFFFF)]
+    #[br(calc = (raw_block_info >> 28) as u32 & 0x3FFFFFFF)]
     pub file_size: u32,
 }
 
+/// Entry in the secondary entry table of dev packages. Its layout is unknown and it isn't merged into
+/// [UEntryHeader](crate::package::UEntryHeader): the reference, type and block info needed to read an entry are all
+/// stored in [EntryHeader], and this table isn't guaranteed to have the same length as the entry table
 #[derive(BinRead, Debug)]
 #[br(big)]
 pub struct EntryHeader2 {
@@ -47,21 +47,21 @@ pub struct PackageHeader {
 pub struct EntryHeader {
     pub reference: u32,
 
-    _thing: u32,
-    #[br(calc = (_thing >> 16) as u8)]
+    pub raw_type_info: u32,
+    #[br(calc = (raw_type_info >> 16) as u8)]
     pub file_type: u8,
-    #[br(calc = (_thing & 0xff) as u8)]
+    #[br(calc = (raw_type_info & 0xff) as u8)]
     pub file_subtype: u8,
 
-    _block_info: u64,
+    pub raw_block_info: u64,
 
-    #[br(calc = _block_info as u32 & 0x3fff)]
+    #[br(calc = raw_block_info as u32 & 0x3fff)]
     pub starting_block: u32,
 
-    #[br(calc = ((_block_info >> 14) as u32 & 0x3FFF) << 4)]
+    #[br(calc = ((raw_block_info >> 14) as u32 & 0x3FFF) << 4)]
     pub starting_block_offset: u32,
 
-    #[br(calc = (_block_info >> 28) as u32 & 0x3FFFFFFF)]
+    #[br(calc = (raw_block_info >> 28) as u32 & 0x3FFFFFFF)]
     pub file_size: u32,
 }
 
@@ -1,30 +1,24 @@
 use std::{
-    collections::hash_map::Entry,
-    fs::File,
-    io::{BufReader, Read, Seek, SeekFrom},
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
-    },
+    io::{Read, Seek, SeekFrom},
+    sync::Arc,
 };
 
 use anyhow::Context;
 use binrw::{BinReaderExt, Endian, VecArgs};
 use parking_lot::RwLock;
-use rustc_hash::FxHashMap;
 
 use super::structs::NamedTagEntryD1;
 use crate::{
     d1_roi::structs::{BlockHeader, EntryHeader, PackageHeader},
-    d2_shared::PackageNamedTagEntry,
-    oodle,
+    d2_shared::{PackageNamedTagEntry, BLOCK_SIZE},
+    oodle::{self, OodleVersion},
     package::{
-        Package, PackageLanguage, ReadSeek, UEntryHeader, UHashTableEntry, BLOCK_CACHE_SIZE,
+        package_path_base, read_raw_header, BlockCache, BlockInfo, Package, PackageLanguage,
+        PackageOpenOptions, ReadSeek, UEntryHeader, UHashTableEntry,
     },
+    tag::{PatchId, PkgId},
 };
 
-pub const BLOCK_SIZE: usize = 0x40000;
-
 pub struct PackageD1RiseOfIron {
     pub header: PackageHeader,
     _entries: Vec<EntryHeader>,
@@ -33,9 +27,9 @@ pub struct PackageD1RiseOfIron {
 
     reader: RwLock<Box<dyn ReadSeek>>,
     path_base: String,
+    options: PackageOpenOptions,
 
-    block_counter: AtomicUsize,
-    block_cache: RwLock<FxHashMap<usize, (usize, Arc<Vec<u8>>)>>,
+    block_cache: BlockCache,
     named_tags: Vec<PackageNamedTagEntry>,
 }
 
@@ -43,10 +37,12 @@ unsafe impl Send for PackageD1RiseOfIron {}
 unsafe impl Sync for PackageD1RiseOfIron {}
 
 impl PackageD1RiseOfIron {
-    pub fn open(path: &str) -> anyhow::Result<PackageD1RiseOfIron> {
-        let reader = BufReader::new(File::open(path)?);
+    pub fn open(path: &str, options: &PackageOpenOptions) -> anyhow::Result<PackageD1RiseOfIron> {
+        let reader = options.open_file(path)?;
 
-        Self::from_reader(path, reader)
+        let mut package = Self::from_reader(path, reader)?;
+        package.options = options.clone();
+        Ok(package)
     }
 
     pub fn from_reader<R: ReadSeek + 'static>(
@@ -77,8 +73,7 @@ impl PackageD1RiseOfIron {
                 .finalize(),
         )?;
 
-        let last_underscore_pos = path.rfind('_').unwrap();
-        let path_base = path[..last_underscore_pos].to_owned();
+        let path_base = package_path_base(path)?;
 
         let entries_unified: Vec<UEntryHeader> = entries
             .iter()
@@ -89,32 +84,36 @@ impl PackageD1RiseOfIron {
                 starting_block: e.starting_block,
                 starting_block_offset: e.starting_block_offset,
                 file_size: e.file_size,
+                raw_type_info: e.raw_type_info,
+                raw_block_info: e.raw_block_info,
             })
             .collect();
 
         Ok(PackageD1RiseOfIron {
             path_base,
             reader: RwLock::new(Box::new(reader)),
+            options: PackageOpenOptions::default(),
             header,
             _entries: entries,
             entries_unified,
             blocks,
-            block_counter: AtomicUsize::default(),
             block_cache: Default::default(),
             // Remap named tags to D2 struct for convenience
             named_tags: named_tags
                 .into_iter()
-                .map(|n: NamedTagEntryD1| PackageNamedTagEntry {
-                    hash: n.hash,
-                    class_hash: n.class_hash,
-                    name: String::from_utf8_lossy(&n.name).into_owned(),
+                .map(|n: NamedTagEntryD1| {
+                    PackageNamedTagEntry::from_fixed_name(n.hash, n.class_hash, &n.name)
                 })
                 .collect(),
         })
     }
 
     fn get_block_raw(&self, block_index: usize) -> anyhow::Result<Vec<u8>> {
-        let bh = &self.blocks[block_index];
+        let bh = self
+            .blocks
+            .get(block_index)
+            .context("Block index out of bounds")?;
+        self.options.check_block_patch(block_index, bh.patch_id)?;
         let mut data = vec![0u8; bh.size as usize];
 
         if self.header.patch_id == bh.patch_id {
@@ -123,13 +122,7 @@ impl PackageD1RiseOfIron {
                 .seek(SeekFrom::Start(bh.offset as u64))?;
             let _ = self.reader.write().read(&mut data)?;
         } else {
-            let mut f = File::open(format!("{}_{}.pkg", self.path_base, bh.patch_id))
-                .with_context(|| {
-                    format!(
-                        "Failed to open package file {}_{}.pkg",
-                        self.path_base, bh.patch_id
-                    )
-                })?;
+            let mut f = self.options.open_patch_file(&self.path_base, bh.patch_id)?;
 
             f.seek(SeekFrom::Start(bh.offset as u64))?;
             let _ = f.read(&mut data)?;
@@ -139,13 +132,14 @@ impl PackageD1RiseOfIron {
     }
 
     fn read_block(&self, block_index: usize) -> anyhow::Result<Vec<u8>> {
-        let bh = &self.blocks[block_index];
+        let bh = self
+            .blocks
+            .get(block_index)
+            .context("Block index out of bounds")?;
         let block_data = self.get_block_raw(block_index)?.to_vec();
 
         Ok(if (bh.flags & 0x1) != 0 {
-            let mut buffer = vec![0u8; BLOCK_SIZE];
-            let _decompressed_size = oodle::decompress_3(&block_data, &mut buffer)?;
-            buffer
+            oodle::decompress_block(OodleVersion::V3, block_index, &block_data, BLOCK_SIZE)?
         } else {
             block_data
         })
@@ -157,12 +151,16 @@ impl Package for PackageD1RiseOfIron {
         Endian::Little // TODO(cohae): Not necessarily
     }
 
-    fn pkg_id(&self) -> u16 {
-        self.header.pkg_id
+    fn pkg_id(&self) -> PkgId {
+        PkgId(self.header.pkg_id)
+    }
+
+    fn patch_id(&self) -> PatchId {
+        PatchId(self.header.patch_id)
     }
 
-    fn patch_id(&self) -> u16 {
-        self.header.patch_id
+    fn format_version(&self) -> u16 {
+        self.header.magic as u16
     }
 
     // TODO(cohae): Fix these APIs, we should just cache the result and only return a slice
@@ -182,41 +180,63 @@ impl Package for PackageD1RiseOfIron {
         self.entries_unified.get(index).cloned()
     }
 
+    fn content_version(&self) -> Option<(u16, u16)> {
+        Some((self.header.version_major, self.header.version_minor))
+    }
+
     fn language(&self) -> PackageLanguage {
         self.header.language
     }
 
-    fn get_block(&self, block_index: usize) -> anyhow::Result<Arc<Vec<u8>>> {
-        let (_, b) = match self.block_cache.write().entry(block_index) {
-            Entry::Occupied(o) => o.get().clone(),
-            Entry::Vacant(v) => {
-                let block = self.read_block(*v.key())?;
-                let b = v
-                    .insert((self.block_counter.load(Ordering::Relaxed), Arc::new(block)))
-                    .clone();
-
-                self.block_counter.store(
-                    self.block_counter.load(Ordering::Relaxed) + 1,
-                    Ordering::Relaxed,
-                );
-
-                b
-            }
-        };
+    fn file_size(&self) -> u64 {
+        self.header.file_size as u64
+    }
+
+    fn raw_header(&self) -> anyhow::Result<Vec<u8>> {
+        read_raw_header(&self.reader, self.header.header_signature_offset)
+    }
 
-        while self.block_cache.read().len() > BLOCK_CACHE_SIZE {
-            let bc = self.block_cache.read();
-            let (oldest, _) = bc
-                .iter()
-                .min_by(|(_, (at, _)), (_, (bt, _))| at.cmp(bt))
-                .unwrap();
+    fn block_info(&self, index: usize) -> Option<BlockInfo> {
+        self.blocks.get(index).map(|b| BlockInfo {
+            index,
+            patch_id: b.patch_id,
+            flags: b.flags,
+            size: b.size,
+            compressed: (b.flags & 0x1) != 0,
+        })
+    }
+
+    fn get_block_raw_bytes(&self, block_index: usize) -> anyhow::Result<Vec<u8>> {
+        self.get_block_raw(block_index)
+    }
 
-            let oldest = *oldest;
-            drop(bc);
+    fn read_block_uncached(&self, block_index: usize) -> anyhow::Result<Vec<u8>> {
+        self.read_block(block_index)
+    }
 
-            self.block_cache.write().remove(&oldest);
-        }
+    fn validate_patches(&self) -> anyhow::Result<()> {
+        self.options.validate_patches(
+            &self.path_base,
+            self.header.patch_id,
+            self.blocks.iter().map(|b| b.patch_id),
+        )
+    }
+
+    fn block_cache_bytes(&self) -> usize {
+        self.block_cache.size_bytes()
+    }
+
+    fn get_block(&self, block_index: usize) -> anyhow::Result<Arc<Vec<u8>>> {
+        self.block_cache
+            .get_or_read(block_index, |i| self.read_block(i))
+    }
 
-        Ok(b)
+    fn with_block(
+        &self,
+        block_index: usize,
+        f: &mut dyn FnMut(&[u8]) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        self.block_cache
+            .with_block(block_index, |i| self.read_block(i), f)
     }
 }
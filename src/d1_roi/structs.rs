@@ -46,21 +46,21 @@ pub struct PackageHeader {
 pub struct EntryHeader {
     pub reference: u32,
 
-    thing: u32,
-    #[br(calc = (thing & 0xffff) as u8)]
+    pub raw_type_info: u32,
+    #[br(calc = (raw_type_info & 0xffff) as u8)]
     pub file_type: u8,
-    #[br(calc = (thing >> 24) as u8)]
+    #[br(calc = (raw_type_info >> 24) as u8)]
     pub file_subtype: u8,
 
-    _block_info: u64,
+    pub raw_block_info: u64,
 
-    #[br(calc = _block_info as u32 & 0x3fff)]
+    #[br(calc = raw_block_info as u32 & 0x3fff)]
     pub starting_block: u32,
 
-    #[br(calc = ((_block_info >> 14) as u32 & 0x3FFF) << 4)]
+    #[br(calc = ((raw_block_info >> 14) as u32 & 0x3FFF) << 4)]
     pub starting_block_offset: u32,
 
-    #[br(calc = (_block_info >> 28) as u32 & 0x3FFFFFFF)]
+    #[br(calc = (raw_block_info >> 28) as u32 & 0x3FFFFFFF)]
     pub file_size: u32,
 }
 
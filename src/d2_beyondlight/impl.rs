@@ -1,16 +1,16 @@
-use std::{
-    fs::File,
-    io::{BufReader, SeekFrom},
-    sync::Arc,
-};
+use std::{io::SeekFrom, sync::Arc};
 
-use anyhow::Context;
 use binrw::{BinReaderExt, Endian, VecArgs};
 
 use crate::{
     d2_beyondlight::structs::PackageHeader,
     d2_shared::{HashTableEntry, PackageCommonD2, PackageNamedTagEntry},
-    package::{Package, ReadSeek, UEntryHeader, UHashTableEntry},
+    package::{
+        read_raw_header, BlockInfo, Package, PackageOpenOptions, ReadSeek, UEntryHeader,
+        UHashTableEntry,
+    },
+    tag::{PatchId, PkgId},
+    version::{check_header_version, HEADER_VERSION_BL},
     GameVersion,
 };
 
@@ -24,11 +24,16 @@ unsafe impl Send for PackageD2BeyondLight {}
 unsafe impl Sync for PackageD2BeyondLight {}
 
 impl PackageD2BeyondLight {
-    pub fn open(path: &str, version: GameVersion) -> anyhow::Result<PackageD2BeyondLight> {
-        let reader =
-            BufReader::new(File::open(path).with_context(|| format!("Cannot find file '{path}'"))?);
+    pub fn open(
+        path: &str,
+        version: GameVersion,
+        options: &PackageOpenOptions,
+    ) -> anyhow::Result<PackageD2BeyondLight> {
+        let reader = options.open_file(path)?;
 
-        Self::from_reader(path, reader, version)
+        let mut package = Self::from_reader(path, reader, version)?;
+        package.common.set_options(options);
+        Ok(package)
     }
 
     pub fn from_reader<R: ReadSeek + 'static>(
@@ -37,6 +42,7 @@ impl PackageD2BeyondLight {
         version: GameVersion,
     ) -> anyhow::Result<PackageD2BeyondLight> {
         let mut reader = reader;
+        check_header_version(&mut reader, HEADER_VERSION_BL)?;
         let header: PackageHeader = reader.read_le()?;
 
         reader.seek(SeekFrom::Start(header.entry_table_offset as _))?;
@@ -71,8 +77,8 @@ impl PackageD2BeyondLight {
             common: PackageCommonD2::new(
                 reader,
                 version,
-                header.pkg_id,
-                header.patch_id,
+                PkgId(header.pkg_id),
+                PatchId(header.patch_id),
                 header.group_id,
                 entries,
                 blocks,
@@ -91,12 +97,20 @@ impl Package for PackageD2BeyondLight {
         Endian::Little // TODO(cohae): Not necessarily
     }
 
-    fn pkg_id(&self) -> u16 {
-        self.common.pkg_id
+    fn pkg_id(&self) -> PkgId {
+        PkgId(self.common.pkg_id)
     }
 
-    fn patch_id(&self) -> u16 {
-        self.common.patch_id
+    fn patch_id(&self) -> PatchId {
+        PatchId(self.common.patch_id)
+    }
+
+    fn format_version(&self) -> u16 {
+        self.header.version.0
+    }
+
+    fn group_id(&self) -> Option<u64> {
+        Some(self.common.group_id)
     }
 
     fn hash64_table(&self) -> Vec<UHashTableEntry> {
@@ -111,6 +125,14 @@ impl Package for PackageD2BeyondLight {
             .collect()
     }
 
+    fn file_size(&self) -> u64 {
+        self.header.file_size as u64
+    }
+
+    fn raw_header(&self) -> anyhow::Result<Vec<u8>> {
+        read_raw_header(&self.common.reader, self.header.header_signature_offset)
+    }
+
     fn named_tags(&self) -> Vec<PackageNamedTagEntry> {
         self.named_tags.clone()
     }
@@ -119,6 +141,10 @@ impl Package for PackageD2BeyondLight {
         &self.common.entries_unified
     }
 
+    fn entries_arc(&self) -> Option<Arc<[UEntryHeader]>> {
+        Some(self.common.entries_unified.clone())
+    }
+
     fn entry(&self, index: usize) -> Option<UEntryHeader> {
         self.common.entries_unified.get(index).cloned()
     }
@@ -126,4 +152,32 @@ impl Package for PackageD2BeyondLight {
     fn get_block(&self, index: usize) -> anyhow::Result<Arc<Vec<u8>>> {
         self.common.get_block(index)
     }
+
+    fn with_block(
+        &self,
+        index: usize,
+        f: &mut dyn FnMut(&[u8]) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        self.common.with_block(index, f)
+    }
+
+    fn block_info(&self, index: usize) -> Option<BlockInfo> {
+        self.common.block_info(index)
+    }
+
+    fn get_block_raw_bytes(&self, index: usize) -> anyhow::Result<Vec<u8>> {
+        Ok(self.common.get_block_raw(index)?.into_owned())
+    }
+
+    fn read_block_uncached(&self, index: usize) -> anyhow::Result<Vec<u8>> {
+        self.common.read_block(index)
+    }
+
+    fn block_cache_bytes(&self) -> usize {
+        self.common.block_cache_bytes()
+    }
+
+    fn validate_patches(&self) -> anyhow::Result<()> {
+        self.common.validate_patches()
+    }
 }
@@ -1,15 +1,16 @@
-use std::{
-    fs::File,
-    io::{BufReader, SeekFrom},
-    sync::Arc,
-};
+use std::{io::SeekFrom, sync::Arc};
 
 use binrw::{BinReaderExt, Endian, VecArgs};
 
 use crate::{
     d2_prebl::structs::PackageHeader,
     d2_shared::{HashTableEntry, PackageCommonD2, PackageNamedTagEntry},
-    package::{Package, ReadSeek, UEntryHeader, UHashTableEntry},
+    package::{
+        read_raw_header, BlockInfo, Package, PackageOpenOptions, ReadSeek, UEntryHeader,
+        UHashTableEntry,
+    },
+    tag::{PatchId, PkgId},
+    version::{check_header_version, HEADER_VERSION_PREBL},
     GameVersion,
 };
 
@@ -24,10 +25,19 @@ unsafe impl Sync for PackageD2PreBL {}
 
 impl PackageD2PreBL {
     pub fn open(path: &str) -> anyhow::Result<PackageD2PreBL> {
+        Self::open_with_options(path, &PackageOpenOptions::default())
+    }
+
+    pub fn open_with_options(
+        path: &str,
+        options: &PackageOpenOptions,
+    ) -> anyhow::Result<PackageD2PreBL> {
         let _span = tracing::trace_span!("PackageD2PreBL::open", path);
-        let reader = BufReader::new(File::open(path)?);
+        let reader = options.open_file(path)?;
 
-        Self::from_reader(path, reader)
+        let mut package = Self::from_reader(path, reader)?;
+        package.common.set_options(options);
+        Ok(package)
     }
 
     pub fn from_reader<R: ReadSeek + 'static>(
@@ -36,6 +46,7 @@ impl PackageD2PreBL {
     ) -> anyhow::Result<PackageD2PreBL> {
         let _span = tracing::trace_span!("PackageD2PreBL::from_reader", path);
         let mut reader = reader;
+        check_header_version(&mut reader, HEADER_VERSION_PREBL)?;
         let header: PackageHeader = reader.read_le()?;
 
         reader.seek(SeekFrom::Start(header.entry_table_offset as u64 - 16))?;
@@ -85,8 +96,8 @@ impl PackageD2PreBL {
             common: PackageCommonD2::new(
                 reader,
                 GameVersion::Destiny2Shadowkeep,
-                header.pkg_id,
-                header.patch_id,
+                PkgId(header.pkg_id),
+                PatchId(header.patch_id),
                 header.group_id,
                 entries,
                 blocks,
@@ -105,12 +116,21 @@ impl Package for PackageD2PreBL {
         Endian::Little // TODO(cohae): Not necessarily
     }
 
-    fn pkg_id(&self) -> u16 {
-        self.common.pkg_id
+    fn pkg_id(&self) -> PkgId {
+        PkgId(self.common.pkg_id)
+    }
+
+    fn patch_id(&self) -> PatchId {
+        PatchId(self.common.patch_id)
     }
 
-    fn patch_id(&self) -> u16 {
-        self.common.patch_id
+    fn format_version(&self) -> u16 {
+        // Already checked against the header magic while reading
+        HEADER_VERSION_PREBL
+    }
+
+    fn group_id(&self) -> Option<u64> {
+        Some(self.common.group_id)
     }
 
     fn hash64_table(&self) -> Vec<UHashTableEntry> {
@@ -125,6 +145,14 @@ impl Package for PackageD2PreBL {
             .collect()
     }
 
+    fn file_size(&self) -> u64 {
+        self.header.file_size as u64
+    }
+
+    fn raw_header(&self) -> anyhow::Result<Vec<u8>> {
+        read_raw_header(&self.common.reader, self.header.header_signature_offset)
+    }
+
     fn named_tags(&self) -> Vec<PackageNamedTagEntry> {
         self.named_tags.clone()
     }
@@ -133,6 +161,10 @@ impl Package for PackageD2PreBL {
         &self.common.entries_unified
     }
 
+    fn entries_arc(&self) -> Option<Arc<[UEntryHeader]>> {
+        Some(self.common.entries_unified.clone())
+    }
+
     fn entry(&self, index: usize) -> Option<UEntryHeader> {
         self.common.entries_unified.get(index).cloned()
     }
@@ -140,4 +172,32 @@ impl Package for PackageD2PreBL {
     fn get_block(&self, index: usize) -> anyhow::Result<Arc<Vec<u8>>> {
         self.common.get_block(index)
     }
+
+    fn with_block(
+        &self,
+        index: usize,
+        f: &mut dyn FnMut(&[u8]) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        self.common.with_block(index, f)
+    }
+
+    fn block_info(&self, index: usize) -> Option<BlockInfo> {
+        self.common.block_info(index)
+    }
+
+    fn get_block_raw_bytes(&self, index: usize) -> anyhow::Result<Vec<u8>> {
+        Ok(self.common.get_block_raw(index)?.into_owned())
+    }
+
+    fn read_block_uncached(&self, index: usize) -> anyhow::Result<Vec<u8>> {
+        self.common.read_block(index)
+    }
+
+    fn block_cache_bytes(&self) -> usize {
+        self.common.block_cache_bytes()
+    }
+
+    fn validate_patches(&self) -> anyhow::Result<()> {
+        self.common.validate_patches()
+    }
 }
@@ -1,49 +1,61 @@
+use std::io::{Read, Seek, SeekFrom};
+#[cfg(feature = "reader")]
 use std::{
     borrow::Cow,
     collections::hash_map::Entry,
-    fs::File,
-    io::{Read, Seek, SeekFrom},
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
-    },
+    io::{Cursor, Write},
+    sync::Arc,
 };
 
-use anyhow::Context;
+#[cfg(feature = "reader")]
+use anyhow::{ensure, Context};
+#[cfg(feature = "reader")]
+use binrw::BinWriterExt;
 use binrw::{BinRead, BinReaderExt, NullString};
+#[cfg(feature = "reader")]
 use parking_lot::RwLock;
+#[cfg(feature = "reader")]
 use rustc_hash::FxHashMap;
 
+use crate::TagHash;
+#[cfg(feature = "reader")]
 use crate::{
     crypto::PkgGcmState,
     oodle,
-    package::{ReadSeek, UEntryHeader, BLOCK_CACHE_SIZE},
-    GameVersion, TagHash,
+    package::{
+        package_path_base, BlockCache, BlockInfo, PackageOpenOptions, PackagePlatform, ReadSeek,
+        UEntryHeader,
+    },
+    source::SourceReader,
+    tag::{PatchId, PkgId},
+    version, GameVersion,
 };
 
+#[cfg(feature = "reader")]
 #[derive(BinRead, Debug, Clone)]
 pub struct EntryHeader {
     pub reference: u32,
 
-    _type_info: u32,
+    pub raw_type_info: u32,
 
-    #[br(calc = (_type_info >> 9) as u8 & 0x7f)]
+    #[br(calc = (raw_type_info >> 9) as u8 & 0x7f)]
     pub file_type: u8,
-    #[br(calc = (_type_info >> 6) as u8 & 0x7)]
+    #[br(calc = (raw_type_info >> 6) as u8 & 0x7)]
     pub file_subtype: u8,
 
-    _block_info: u64,
+    pub raw_block_info: u64,
 
-    #[br(calc = _block_info as u32 & 0x3fff)]
+    #[br(calc = raw_block_info as u32 & 0x3fff)]
     pub starting_block: u32,
 
-    #[br(calc = ((_block_info >> 14) as u32 & 0x3FFF) << 4)]
+    #[br(calc = ((raw_block_info >> 14) as u32 & 0x3FFF) << 4)]
     pub starting_block_offset: u32,
 
-    #[br(calc = (_block_info >> 28) as u32)]
+    #[br(calc = (raw_block_info >> 28) as u32)]
     pub file_size: u32,
 }
 
+#[cfg(feature = "reader")]
 #[derive(BinRead, Debug, Clone)]
 pub struct BlockHeader {
     pub offset: u32,
@@ -54,6 +66,7 @@ pub struct BlockHeader {
     pub gcm_tag: [u8; 16],
 }
 
+#[cfg(feature = "reader")]
 #[derive(BinRead, Debug, Clone)]
 pub struct HashTableEntry {
     pub hash64: u64,
@@ -63,10 +76,12 @@ pub struct HashTableEntry {
 
 pub const BLOCK_SIZE: usize = 0x40000;
 
+#[cfg(feature = "reader")]
 pub struct PackageCommonD2 {
     pub(crate) version: GameVersion,
     pub(crate) pkg_id: u16,
     pub(crate) patch_id: u16,
+    pub(crate) group_id: u64,
 
     pub(crate) gcm: RwLock<PkgGcmState>,
     pub(crate) _entries: Vec<EntryHeader>,
@@ -76,27 +91,26 @@ pub struct PackageCommonD2 {
 
     pub(crate) reader: RwLock<Box<dyn ReadSeek>>,
     pub(crate) path_base: String,
+    pub(crate) options: PackageOpenOptions,
 
-    /// Used for purging old blocks
-    pub(crate) block_counter: AtomicUsize,
-    pub(crate) block_cache: RwLock<FxHashMap<usize, (usize, Arc<Vec<u8>>)>>,
-    pub(crate) file_handles: RwLock<FxHashMap<usize, File>>,
+    pub(crate) block_cache: BlockCache,
+    pub(crate) file_handles: RwLock<FxHashMap<usize, SourceReader>>,
 }
 
+#[cfg(feature = "reader")]
 impl PackageCommonD2 {
     pub fn new<R: ReadSeek + 'static>(
         reader: R,
         version: GameVersion,
-        pkg_id: u16,
-        patch_id: u16,
+        PkgId(pkg_id): PkgId,
+        PatchId(patch_id): PatchId,
         group_id: u64,
         entries: Vec<EntryHeader>,
         blocks: Vec<BlockHeader>,
         hashes: Vec<HashTableEntry>,
         path: String,
     ) -> anyhow::Result<PackageCommonD2> {
-        let last_underscore_pos = path.rfind('_').unwrap();
-        let path_base = path[..last_underscore_pos].to_owned();
+        let path_base = package_path_base(&path)?;
 
         let entries_unified: Vec<UEntryHeader> = entries
             .iter()
@@ -107,6 +121,8 @@ impl PackageCommonD2 {
                 starting_block: e.starting_block,
                 starting_block_offset: e.starting_block_offset,
                 file_size: e.file_size,
+                raw_type_info: e.raw_type_info,
+                raw_block_info: e.raw_block_info,
             })
             .collect();
 
@@ -114,6 +130,7 @@ impl PackageCommonD2 {
             version,
             pkg_id,
             patch_id,
+            group_id,
             gcm: RwLock::new(PkgGcmState::new(pkg_id, version, group_id)),
             _entries: entries,
             entries_unified: entries_unified.into(),
@@ -121,23 +138,27 @@ impl PackageCommonD2 {
             hashes,
             reader: RwLock::new(Box::new(reader)),
             path_base,
-            block_counter: AtomicUsize::default(),
+            options: PackageOpenOptions::default(),
             block_cache: Default::default(),
             file_handles: Default::default(),
         })
     }
 
-    fn get_block_raw(&self, block_index: usize) -> anyhow::Result<Cow<[u8]>> {
+    pub(crate) fn get_block_raw(&self, block_index: usize) -> anyhow::Result<Cow<[u8]>> {
         let _span = tracing::debug_span!("PackageCommonD2::get_block_raw", block_index).entered();
 
-        let bh = &self.blocks[block_index];
+        let bh = self
+            .blocks
+            .get(block_index)
+            .context("Block index out of bounds")?;
+        self.options.check_block_patch(block_index, bh.patch_id)?;
         let mut data = vec![0u8; bh.size as usize];
 
         if self.patch_id == bh.patch_id {
-            self.reader
-                .write()
-                .seek(SeekFrom::Start(bh.offset as u64))?;
-            self.reader.write().read_exact(&mut data)?;
+            // Seek and read under a single lock, otherwise another thread could move the cursor in between
+            let mut reader = self.reader.write();
+            reader.seek(SeekFrom::Start(bh.offset as u64))?;
+            reader.read_exact(&mut data)?;
         } else {
             match self.file_handles.write().entry(bh.patch_id as _) {
                 Entry::Occupied(mut f) => {
@@ -146,13 +167,7 @@ impl PackageCommonD2 {
                     f.read_exact(&mut data)?;
                 }
                 Entry::Vacant(e) => {
-                    let f = File::open(format!("{}_{}.pkg", self.path_base, bh.patch_id))
-                        .with_context(|| {
-                            format!(
-                                "Failed to open package file {}_{}.pkg",
-                                self.path_base, bh.patch_id
-                            )
-                        })?;
+                    let f = self.options.open_patch_file(&self.path_base, bh.patch_id)?;
 
                     let f = e.insert(f);
                     f.seek(SeekFrom::Start(bh.offset as u64))?;
@@ -164,11 +179,37 @@ impl PackageCommonD2 {
         Ok(Cow::Owned(data))
     }
 
-    /// Reads, decrypts and decompresses the specified block
-    fn read_block(&self, block_index: usize) -> anyhow::Result<Vec<u8>> {
+    pub fn block_info(&self, index: usize) -> Option<BlockInfo> {
+        self.blocks.get(index).map(|b| BlockInfo {
+            index,
+            patch_id: b.patch_id,
+            flags: b.flags,
+            size: b.size,
+            compressed: (b.flags & 0x1) != 0,
+        })
+    }
+
+    pub fn validate_patches(&self) -> anyhow::Result<()> {
+        self.options.validate_patches(
+            &self.path_base,
+            self.patch_id,
+            self.blocks.iter().map(|b| b.patch_id),
+        )
+    }
+
+    pub fn block_cache_bytes(&self) -> usize {
+        self.block_cache.size_bytes()
+    }
+
+    /// Reads, decrypts and decompresses the specified block, bypassing the block cache
+    pub(crate) fn read_block(&self, block_index: usize) -> anyhow::Result<Vec<u8>> {
         let _span = tracing::debug_span!("PackageCommonD2::read_block", block_index).entered();
 
-        let bh = self.blocks[block_index].clone();
+        let bh = self
+            .blocks
+            .get(block_index)
+            .context("Block index out of bounds")?
+            .clone();
 
         let mut block_data = self.get_block_raw(block_index)?.to_vec();
 
@@ -177,7 +218,7 @@ impl PackageCommonD2 {
                 tracing::debug_span!("PackageCommonD2::get_block_raw decrypt", block_index)
                     .entered();
             self.gcm
-                .write()
+                .read()
                 .decrypt_block_in_place(bh.flags, &bh.gcm_tag, &mut block_data)?;
         };
 
@@ -186,26 +227,13 @@ impl PackageCommonD2 {
                 tracing::debug_span!("PackageCommonD2::get_block_raw decompress", block_index)
                     .entered();
 
-            let mut buffer = vec![0u8; BLOCK_SIZE];
-            let _decompressed_size = match self.version {
-                // Destiny 1
-                GameVersion::DestinyInternalAlpha
-                | GameVersion::DestinyTheTakenKing
-                | GameVersion::DestinyRiseOfIron => oodle::decompress_3,
-
-                // Destiny 2 (Red War - Beyond Light)
-                GameVersion::Destiny2Beta
-                | GameVersion::Destiny2Forsaken
-                | GameVersion::Destiny2Shadowkeep => oodle::decompress_3,
-
-                // Destiny 2 (Beyond Light - Latest)
-                GameVersion::Destiny2BeyondLight
-                | GameVersion::Destiny2WitchQueen
-                | GameVersion::Destiny2Lightfall
-                | GameVersion::Destiny2TheFinalShape => oodle::decompress_9,
-            }(&block_data, &mut buffer)?;
-
-            buffer
+            // Block headers don't store the decompressed size, compressed blocks always fill a whole block
+            oodle::decompress_block(
+                self.version.oodle_version(),
+                block_index,
+                &block_data,
+                BLOCK_SIZE,
+            )?
         } else {
             block_data
         };
@@ -213,39 +241,26 @@ impl PackageCommonD2 {
         Ok(decompressed_data)
     }
 
+    /// Applies the options a package was opened with, including any group keys they carry
+    pub(crate) fn set_options(&mut self, options: &PackageOpenOptions) {
+        self.options = options.clone();
+        *self.gcm.get_mut() =
+            PkgGcmState::with_keys(self.pkg_id, self.version, self.group_id, &self.options.keys);
+    }
+
     pub fn get_block(&self, block_index: usize) -> anyhow::Result<Arc<Vec<u8>>> {
         let _span = tracing::debug_span!("PackageCommonD2::get_block", block_index).entered();
-        let (_, b) = match self.block_cache.write().entry(block_index) {
-            Entry::Occupied(o) => o.get().clone(),
-            Entry::Vacant(v) => {
-                let block = self.read_block(*v.key())?;
-                let b = v
-                    .insert((self.block_counter.load(Ordering::Relaxed), Arc::new(block)))
-                    .clone();
-
-                self.block_counter.store(
-                    self.block_counter.load(Ordering::Relaxed) + 1,
-                    Ordering::Relaxed,
-                );
-
-                b
-            }
-        };
-
-        while self.block_cache.read().len() > BLOCK_CACHE_SIZE {
-            let bc = self.block_cache.read();
-            let (oldest, _) = bc
-                .iter()
-                .min_by(|(_, (at, _)), (_, (bt, _))| at.cmp(bt))
-                .unwrap();
-
-            let oldest = *oldest;
-            drop(bc);
-
-            self.block_cache.write().remove(&oldest);
-        }
+        self.block_cache
+            .get_or_read(block_index, |i| self.read_block(i))
+    }
 
-        Ok(b)
+    pub fn with_block(
+        &self,
+        block_index: usize,
+        f: &mut dyn FnMut(&[u8]) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        self.block_cache
+            .with_block(block_index, |i| self.read_block(i), f)
     }
 }
 
@@ -254,6 +269,27 @@ pub struct PackageNamedTagEntry {
     pub hash: TagHash,
     pub class_hash: u32,
     pub name: String,
+    /// The name as stored in the package. For Destiny 1 this is the entire fixed-size name buffer, including anything
+    /// after the null terminator
+    pub raw_name: Vec<u8>,
+}
+
+impl PackageNamedTagEntry {
+    /// Creates an entry from a fixed-size, null-terminated name buffer
+    #[cfg(feature = "reader")]
+    pub(crate) fn from_fixed_name(hash: TagHash, class_hash: u32, raw_name: &[u8]) -> Self {
+        let name_len = raw_name
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(raw_name.len());
+
+        Self {
+            hash,
+            class_hash,
+            name: String::from_utf8_lossy(&raw_name[..name_len]).into_owned(),
+            raw_name: raw_name.to_vec(),
+        }
+    }
 }
 
 impl BinRead for PackageNamedTagEntry {
@@ -278,6 +314,466 @@ impl BinRead for PackageNamedTagEntry {
             hash,
             class_hash,
             name: name_cstring.to_string(),
+            raw_name: name_cstring.0,
         })
     }
 }
+
+#[cfg(feature = "reader")]
+struct PackageWriterEntry {
+    reference: u32,
+    file_type: u8,
+    file_subtype: u8,
+    data: Vec<u8>,
+}
+
+/// Builds a Destiny 2 package from a set of entries.
+///
+/// Both the pre-Beyond Light and the Beyond Light and later package formats are supported. Block hashes and the header
+/// signature are not generated, so packages written by this are only readable by tools, not by the game itself.
+#[cfg(feature = "reader")]
+pub struct PackageWriter {
+    pub version: GameVersion,
+    pub platform: PackagePlatform,
+    pub pkg_id: u16,
+    pub patch_id: u16,
+    pub group_id: u64,
+    pub build_time: u64,
+
+    /// Compress blocks with Oodle 9 (Kraken). Blocks are stored uncompressed if the loaded Oodle library can't
+    /// compress, and for pre-Beyond Light packages, which are decompressed with Oodle 3
+    pub compress: bool,
+    /// Encrypt blocks using the external key registered for `group_id`, or the builtin key if there is none
+    pub encrypt: bool,
+
+    entries: Vec<PackageWriterEntry>,
+    named_tags: Vec<PackageNamedTagEntry>,
+    hashes: Vec<HashTableEntry>,
+}
+
+#[cfg(feature = "reader")]
+impl PackageWriter {
+    const HEADER_SIZE: usize = 0x130;
+    const HEADER_SIZE_PREBL: usize = 0x170;
+    const ENTRY_HEADER_SIZE: usize = 16;
+    const BLOCK_HEADER_SIZE: usize = 48;
+    const MAX_BLOCKS: usize = 0x3fff;
+
+    pub fn new(
+        version: GameVersion,
+        platform: PackagePlatform,
+        pkg_id: u16,
+    ) -> anyhow::Result<PackageWriter> {
+        ensure!(
+            version.is_d2(),
+            "Writing packages is not supported for {}",
+            version.name()
+        );
+        ensure!(
+            version::d2_header_platform_id(platform).is_some(),
+            "Destiny 2 packages can't be written for platform {platform}"
+        );
+
+        Ok(PackageWriter {
+            version,
+            platform,
+            pkg_id,
+            patch_id: 0,
+            group_id: 0,
+            build_time: 0,
+            compress: true,
+            encrypt: false,
+            entries: vec![],
+            named_tags: vec![],
+            hashes: vec![],
+        })
+    }
+
+    /// Adds an entry to the package, returning the tag it will be addressable by
+    pub fn add_entry(
+        &mut self,
+        reference: u32,
+        file_type: u8,
+        file_subtype: u8,
+        data: Vec<u8>,
+    ) -> anyhow::Result<TagHash> {
+        let tag = TagHash::try_new(self.pkg_id, self.entries.len() as u16)
+            .filter(|_| self.entries.len() < 8192)
+            .context("Package is full")?;
+
+        ensure!(file_type <= 0x7f, "File type {file_type} is out of range");
+        ensure!(
+            file_subtype <= 0x7,
+            "File subtype {file_subtype} is out of range"
+        );
+        ensure!(
+            data.len() < (1 << 36),
+            "Entry is too large ({} bytes)",
+            data.len()
+        );
+
+        self.entries.push(PackageWriterEntry {
+            reference,
+            file_type,
+            file_subtype,
+            data,
+        });
+
+        Ok(tag)
+    }
+
+    pub fn add_named_tag(&mut self, tag: PackageNamedTagEntry) {
+        self.named_tags.push(tag);
+    }
+
+    pub fn add_hash64(&mut self, hash64: u64, hash32: TagHash, reference: TagHash) {
+        self.hashes.push(HashTableEntry {
+            hash64,
+            hash32,
+            reference,
+        });
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> anyhow::Result<()> {
+        let _span = tracing::debug_span!("PackageWriter::write", pkg_id = self.pkg_id).entered();
+
+        // Lay out all entries in one continuous stream, aligned to 16 bytes
+        let mut stream = vec![];
+        let mut entry_table = Cursor::new(vec![]);
+        for e in &self.entries {
+            let offset = stream.len();
+            let starting_block = (offset / BLOCK_SIZE) as u64;
+            let starting_block_offset = (offset % BLOCK_SIZE) as u64;
+
+            let type_info = ((e.file_type as u32) << 9) | ((e.file_subtype as u32) << 6);
+            let block_info = starting_block
+                | ((starting_block_offset >> 4) << 14)
+                | ((e.data.len() as u64) << 28);
+
+            entry_table.write_le(&e.reference)?;
+            entry_table.write_le(&type_info)?;
+            entry_table.write_le(&block_info)?;
+
+            stream.extend_from_slice(&e.data);
+            stream.resize(stream.len().next_multiple_of(16), 0);
+        }
+
+        let block_count = stream.len().div_ceil(BLOCK_SIZE);
+        ensure!(
+            block_count <= Self::MAX_BLOCKS,
+            "Package needs {block_count} blocks, but only {} are addressable",
+            Self::MAX_BLOCKS
+        );
+
+        let entry_table_size = self.entries.len() * Self::ENTRY_HEADER_SIZE;
+        let block_table_size = block_count * Self::BLOCK_HEADER_SIZE;
+        let (entry_table_offset, block_table_offset, misc_offset) = if self.version.is_postbl() {
+            let entry_table_offset = Self::HEADER_SIZE;
+            let block_table_offset = entry_table_offset + entry_table_size;
+            (
+                entry_table_offset,
+                block_table_offset,
+                block_table_offset + block_table_size,
+            )
+        } else {
+            // Pre-BL tables are preceded by a 16 byte entry table header and a 32 byte block table header
+            let entry_table_offset = Self::HEADER_SIZE_PREBL + 16;
+            let block_table_offset = entry_table_offset + entry_table_size + 32;
+            (
+                entry_table_offset,
+                block_table_offset,
+                block_table_offset + block_table_size,
+            )
+        };
+
+        // Pre-BL packages start their misc data with the sizes and offsets of the named tag and hash64 tables.
+        // Both offsets are relative to their offset field, plus 16
+        let mut misc = Cursor::new(vec![]);
+        let tables_offset = if self.version.is_postbl() { 0 } else { 0x50 };
+        let (tables, h64_table_offset) = self.write_misc_tables()?;
+        if !self.version.is_postbl() {
+            misc.seek(SeekFrom::Start(0x10))?;
+            misc.write_le(&(self.named_tags.len() as u64))?;
+            misc.write_le(&((tables_offset - 0x18 - 16) as u64))?;
+            misc.seek(SeekFrom::Start(0x30))?;
+            misc.write_le(&(self.hashes.len() as u64))?;
+            misc.write_le(&((tables_offset + h64_table_offset - 0x38 - 16) as u64))?;
+        }
+        misc.seek(SeekFrom::Start(tables_offset as u64))?;
+        misc.write_all(&tables)?;
+
+        let data_offset = (misc_offset + misc.get_ref().len()).next_multiple_of(16);
+        misc.get_mut().resize(data_offset - misc_offset, 0);
+
+        // The pre-BL reader decrypts Forsaken packages with the Shadowkeep nonce
+        let gcm_version = match self.version {
+            GameVersion::Destiny2Forsaken => GameVersion::Destiny2Shadowkeep,
+            v => v,
+        };
+        let mut gcm = PkgGcmState::new(self.pkg_id, gcm_version, self.group_id);
+        let mut block_table = Cursor::new(vec![]);
+        let mut block_data = vec![];
+        for chunk in stream.chunks(BLOCK_SIZE) {
+            let mut flags = 0u16;
+            let mut data = None;
+            if self.compress && self.version.is_postbl() {
+                // Blocks are always decompressed into a full BLOCK_SIZE buffer
+                let mut padded = chunk.to_vec();
+                padded.resize(BLOCK_SIZE, 0);
+
+                let mut compressed = vec![0u8; oodle::compress_bound(BLOCK_SIZE)];
+                if let Some(size) = oodle::compress_9(&padded, &mut compressed) {
+                    if size < chunk.len() {
+                        compressed.truncate(size);
+                        data = Some(compressed);
+                        flags |= 0x1;
+                    }
+                }
+            }
+            let mut data = data.unwrap_or_else(|| chunk.to_vec());
+
+            let mut gcm_tag = [0u8; 16];
+            if self.encrypt {
+                flags |= 0x2;
+                if gcm.has_extra_key() {
+                    flags |= 0x8;
+                }
+
+                gcm_tag = gcm.encrypt_block_in_place(flags, &mut data)?;
+            }
+
+            block_table.write_le(&((data_offset + block_data.len()) as u32))?;
+            block_table.write_le(&(data.len() as u32))?;
+            block_table.write_le(&self.patch_id)?;
+            block_table.write_le(&flags)?;
+            block_table.write_le(&[0u8; 20])?;
+            block_table.write_le(&gcm_tag)?;
+
+            block_data.extend_from_slice(&data);
+            block_data.resize(block_data.len().next_multiple_of(16), 0);
+        }
+
+        let file_size = data_offset + block_data.len();
+        ensure!(
+            file_size <= u32::MAX as usize,
+            "Package is too large ({file_size} bytes)"
+        );
+
+        let platform_id = version::d2_header_platform_id(self.platform)
+            .context("Unsupported package platform")?;
+        let header = if self.version.is_postbl() {
+            let mut header = Cursor::new(vec![0u8; Self::HEADER_SIZE]);
+            header.write_le(&(version::HEADER_VERSION_BL, platform_id))?;
+            header.seek(SeekFrom::Start(0x8))?;
+            header.write_le(&self.group_id)?;
+            header.seek(SeekFrom::Start(0x10))?;
+            header.write_le(&self.pkg_id)?;
+            header.seek(SeekFrom::Start(0x20))?;
+            header.write_le(&self.build_time)?;
+            header.seek(SeekFrom::Start(0x30))?;
+            header.write_le(&self.patch_id)?;
+            header.seek(SeekFrom::Start(0x60))?;
+            header.write_le(&(self.entries.len() as u32))?;
+            header.write_le(&(entry_table_offset as u32))?;
+            header.write_le(&(block_count as u32))?;
+            header.write_le(&(block_table_offset as u32))?;
+            // The reader adds 0x30 and 0x50 to these offsets respectively
+            header.seek(SeekFrom::Start(0x78))?;
+            header.write_le(&(self.named_tags.len() as u32))?;
+            header.write_le(&((misc_offset - 0x30) as u32))?;
+            header.seek(SeekFrom::Start(0xb8))?;
+            header.write_le(&(self.hashes.len() as u32))?;
+            header.write_le(&((misc_offset + h64_table_offset - 0x50) as u32))?;
+            header.seek(SeekFrom::Start(0x120))?;
+            header.write_le(&(file_size as u32))?;
+            header
+        } else {
+            let mut header = Cursor::new(vec![0u8; Self::HEADER_SIZE_PREBL + 16]);
+            header.write_le(&(version::HEADER_VERSION_PREBL, platform_id))?;
+            header.write_le(&self.pkg_id)?;
+            header.seek(SeekFrom::Start(0x8))?;
+            header.write_le(&self.group_id)?;
+            header.write_le(&self.build_time)?;
+            header.seek(SeekFrom::Start(0x20))?;
+            header.write_le(&self.patch_id)?;
+            // The beta reader uses the table offsets from here, later versions only the sizes
+            header.seek(SeekFrom::Start(0xb4))?;
+            header.write_le(&(self.entries.len() as u32))?;
+            header.write_le(&(entry_table_offset as u32))?;
+            header.seek(SeekFrom::Start(0xd0))?;
+            header.write_le(&(block_count as u32))?;
+            header.write_le(&(block_table_offset as u32))?;
+            // Only the beta doesn't have misc data, its reader ignores the offset
+            header.seek(SeekFrom::Start(0xf0))?;
+            header.write_le(&(misc_offset as u32))?;
+            // The reader adds 96 to the entry table offset
+            header.seek(SeekFrom::Start(0x110))?;
+            header.write_le(&((entry_table_offset - 96) as u32))?;
+            header.seek(SeekFrom::Start(0x164))?;
+            header.write_le(&(file_size as u32))?;
+            // Entry table header, of which only the entry count is read
+            header.seek(SeekFrom::Start(Self::HEADER_SIZE_PREBL as u64))?;
+            header.write_le(&(self.entries.len() as u32))?;
+            header
+        };
+
+        writer.write_all(header.get_ref())?;
+        writer.write_all(entry_table.get_ref())?;
+        if !self.version.is_postbl() {
+            writer.write_all(&[0u8; 32])?;
+        }
+        writer.write_all(block_table.get_ref())?;
+        writer.write_all(misc.get_ref())?;
+        writer.write_all(&block_data)?;
+
+        Ok(())
+    }
+
+    /// Writes the named tag table followed by the hash64 table, returning the data and the offset of the hash64 table
+    /// within it. The hash64 table is aligned to 16 bytes, so the data should be placed at a 16 byte aligned offset
+    fn write_misc_tables(&self) -> anyhow::Result<(Vec<u8>, usize)> {
+        // Named tag entries point to their name relative to the name offset field
+        let mut misc = Cursor::new(vec![]);
+        let mut names = vec![];
+        let names_offset = self.named_tags.len() * 16;
+        for (i, n) in self.named_tags.iter().enumerate() {
+            let name_offset_field = i * 16 + 8;
+            misc.write_le(&n.hash)?;
+            misc.write_le(&n.class_hash)?;
+            misc.write_le(&((names_offset + names.len() - name_offset_field) as u64))?;
+            names.extend_from_slice(n.name.as_bytes());
+            names.push(0);
+        }
+        misc.write_all(&names)?;
+
+        let h64_table_offset = misc.get_ref().len().next_multiple_of(16);
+        misc.get_mut().resize(h64_table_offset, 0);
+        misc.seek(SeekFrom::End(0))?;
+        for h in &self.hashes {
+            misc.write_le(&h.hash64)?;
+            misc.write_le(&h.hash32)?;
+            misc.write_le(&h.reference)?;
+        }
+
+        Ok((misc.into_inner(), h64_table_offset))
+    }
+}
+
+#[cfg(all(test, feature = "reader"))]
+mod tests {
+    use clap::ValueEnum;
+
+    use super::*;
+    use crate::test_util;
+
+    /// Small entries sharing a block, one spanning several blocks and an empty one
+    fn test_entries() -> Vec<Vec<u8>> {
+        vec![
+            b"first entry".to_vec(),
+            (0..=255u8).collect(),
+            (0..600_000u32).map(|i| (i % 251) as u8).collect(),
+            vec![],
+            b"last entry".to_vec(),
+        ]
+    }
+
+    /// Writes a package, checks every entry reads back and that writing it again from what was read gives the exact
+    /// same file
+    fn roundtrip(version: GameVersion, encrypt: bool) {
+        let entries = test_entries();
+
+        let mut writer = PackageWriter::new(version, PackagePlatform::Windows, 0x123).unwrap();
+        writer.compress = false;
+        writer.encrypt = encrypt;
+        writer.group_id = 0x1122334455667788;
+        for (i, data) in entries.iter().enumerate() {
+            writer
+                .add_entry(0x80800000 | i as u32, 8, 0, data.clone())
+                .unwrap();
+        }
+        // The beta reader doesn't read the named tag and hash64 tables
+        if version != GameVersion::Destiny2Beta {
+            writer.add_named_tag(PackageNamedTagEntry {
+                hash: TagHash::new(0x123, 1),
+                class_hash: 0x80809999,
+                name: "named".to_string(),
+                raw_name: b"named".to_vec(),
+            });
+            writer.add_hash64(
+                0x1234567890abcdef,
+                TagHash::new(0x123, 2),
+                TagHash(0x80800002),
+            );
+        }
+
+        let data = test_util::write(&writer);
+        let package = test_util::open_bytes_as(version, data.clone()).unwrap();
+
+        assert_eq!(u16::from(package.pkg_id()), 0x123);
+        assert_eq!(package.group_id(), Some(0x1122334455667788));
+        assert_eq!(package.entries().len(), entries.len());
+        for (i, expected) in entries.iter().enumerate() {
+            assert_eq!(package.entries()[i].reference, 0x80800000 | i as u32);
+            assert_eq!(
+                &package.read_entry(i).unwrap(),
+                expected,
+                "{version:?} entry {i}"
+            );
+        }
+
+        let mut rewriter =
+            PackageWriter::new(version, PackagePlatform::Windows, package.pkg_id().into()).unwrap();
+        rewriter.compress = false;
+        rewriter.encrypt = encrypt;
+        rewriter.group_id = package.group_id().unwrap();
+        for (i, e) in package.entries().iter().enumerate() {
+            rewriter
+                .add_entry(
+                    e.reference,
+                    e.file_type,
+                    e.file_subtype,
+                    package.read_entry(i).unwrap(),
+                )
+                .unwrap();
+        }
+        for n in package.named_tags() {
+            rewriter.add_named_tag(n);
+        }
+        for h in package.hash64_table() {
+            rewriter.add_hash64(h.hash64, h.hash32, h.reference);
+        }
+
+        assert!(
+            test_util::write(&rewriter) == data,
+            "{version:?} rewrite differs"
+        );
+    }
+
+    #[test]
+    fn roundtrip_d2() {
+        for &version in GameVersion::value_variants().iter().filter(|v| v.is_d2()) {
+            roundtrip(version, false);
+            roundtrip(version, true);
+        }
+    }
+
+    #[test]
+    fn writer_rejects_d1() {
+        for &version in GameVersion::value_variants().iter().filter(|v| v.is_d1()) {
+            assert!(
+                PackageWriter::new(version, PackagePlatform::Windows, 0x123).is_err(),
+                "{version:?} should not be writable"
+            );
+        }
+    }
+
+    #[test]
+    fn writer_rejects_non_d2_platforms() {
+        for platform in [PackagePlatform::PS3, PackagePlatform::X360] {
+            assert!(
+                PackageWriter::new(GameVersion::Destiny2Shadowkeep, platform, 0x123).is_err(),
+                "{platform} should not be writable"
+            );
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use std::fmt::{Display, Formatter};
+
+use itertools::Itertools;
+
+use crate::version::probe_header;
+
+/// Errors that callers might want to handle specifically.
+/// These are returned wrapped in [anyhow::Error], use `downcast_ref::<PackageError>()` to check for them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PackageError {
+    /// The operation was cancelled through its cancellation token
+    Cancelled,
+    /// A compressed block was read, but the crate was built without the `oodle` feature
+    OodleMissing,
+    /// Oodle failed to decompress a block. `code` is the raw value returned by `OodleLZ_Decompress`
+    OodleDecodeFailed {
+        code: i64,
+        block: usize,
+        compressed_len: usize,
+        expected_len: usize,
+    },
+    /// The package header has a different version than the one expected by the [GameVersion](crate::GameVersion) it
+    /// was opened with
+    VersionMismatch { expected: u16, found: u16 },
+    /// Blocks reference patch files that don't exist or can't be opened
+    PatchFileMissing {
+        path_base: String,
+        patch_ids: Vec<u16>,
+        /// Paths the missing patch files were expected at, in the same order as `patch_ids`
+        paths: Vec<String>,
+    },
+    /// The package filename doesn't end with a patch number (eg. `_0.pkg`), so its patch files can't be located
+    UnrecognizedFilename { path: String },
+}
+
+impl Display for PackageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PackageError::Cancelled => f.write_str("Operation was cancelled"),
+            PackageError::OodleMissing => f.write_str(
+                "Cannot decompress block, destiny-pkg was built without the `oodle` feature",
+            ),
+            PackageError::OodleDecodeFailed {
+                code,
+                block,
+                compressed_len,
+                expected_len,
+            } => write!(
+                f,
+                "Failed to decompress block {block} ({compressed_len} bytes, expected {expected_len} bytes \
+                 decompressed), Oodle returned {code}"
+            ),
+            PackageError::VersionMismatch { expected, found } => {
+                write!(
+                    f,
+                    "Package header version {found} does not match the expected version {expected}"
+                )?;
+
+                let candidates = probe_header(&found.to_le_bytes());
+                if !candidates.is_empty() {
+                    write!(
+                        f,
+                        ", this package looks like it's from {}",
+                        candidates.iter().map(|v| v.id()).join("/")
+                    )?;
+                }
+
+                Ok(())
+            }
+            PackageError::PatchFileMissing { paths, .. } => {
+                write!(f, "Missing patch files: {}", paths.join(", "))
+            }
+            PackageError::UnrecognizedFilename { path } => write!(
+                f,
+                "Unrecognized package filename '{path}', expected a name ending in _<patch>.pkg"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PackageError {}
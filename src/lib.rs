@@ -1,23 +1,47 @@
 extern crate core;
 
-mod crypto;
 mod d2_shared;
-mod oodle;
 
+#[cfg(feature = "reader")]
 mod d1_internal_alpha;
+#[cfg(feature = "reader")]
 mod d1_legacy;
+#[cfg(feature = "reader")]
 mod d1_roi;
+#[cfg(feature = "reader")]
 mod d2_beta;
+#[cfg(feature = "reader")]
 mod d2_beyondlight;
+#[cfg(feature = "reader")]
 mod d2_prebl;
 
+#[cfg(feature = "reader")]
+pub mod crypto;
+pub mod error;
+#[cfg(feature = "reader")]
 pub mod manager;
+pub mod oodle;
 pub mod package;
+#[cfg(feature = "reader")]
+pub mod source;
 pub mod tag;
+pub mod version;
+#[cfg(feature = "watch")]
+pub mod watcher;
+
+#[cfg(all(test, feature = "reader"))]
+mod test_util;
 
 pub use binrw::Endian;
+#[cfg(feature = "reader")]
 pub use d2_prebl::PackageD2PreBL;
 pub use d2_shared::PackageNamedTagEntry;
+#[cfg(feature = "reader")]
+pub use d2_shared::PackageWriter;
+pub use error::PackageError;
+#[cfg(feature = "reader")]
 pub use manager::PackageManager;
-pub use package::{GameVersion, Package};
-pub use tag::{TagHash, TagHash64};
+pub use package::GameVersion;
+#[cfg(feature = "reader")]
+pub use package::{Package, PackageOpenOptions};
+pub use tag::{PatchId, PkgId, TagHash, TagHash64};
@@ -1,15 +1,19 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     fmt::Display,
     fs,
-    io::Cursor,
+    hash::{Hash, Hasher},
+    io::{Cursor, Read, Write},
     path::{Path, PathBuf},
     str::FromStr,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
     time::SystemTime,
 };
 
-use anyhow::Context;
+use anyhow::{ensure, Context};
 use binrw::{BinRead, BinReaderExt};
 use itertools::Itertools;
 use parking_lot::RwLock;
@@ -18,32 +22,290 @@ use rustc_hash::FxHashMap;
 use tracing::{debug_span, error, info, warn};
 
 use crate::{
-    d2_shared::PackageNamedTagEntry,
-    oodle,
-    package::{GameVersion, Package, PackagePlatform, UEntryHeader},
+    crypto::{self, PackageKeys},
+    d2_shared::{PackageNamedTagEntry, PackageWriter},
+    error::PackageError,
+    package::{
+        classify_file, package_path_base, GameVersion, Package, PackageLanguage,
+        PackageOpenOptions, PackagePlatform, PatchNamingFn, UEntryHeader,
+    },
+    source::PackageSource,
     tag::TagHash64,
-    TagHash,
+    version, TagHash,
 };
 
+/// The amount of entries addressable by a [TagHash] within a single package
+pub const MAX_ENTRIES_PER_PACKAGE: usize = 8192;
+
 #[derive(Clone)]
 pub struct HashTableEntryShort {
     pub hash32: TagHash,
     pub reference: TagHash,
 }
 
+/// Hashing algorithms available to [PackageManager::hash_tag]
+#[cfg(any(feature = "xxh3", feature = "sha256"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgo {
+    /// 128-bit XXH3, fast but not cryptographically secure. Useful for deduplication
+    #[cfg(feature = "xxh3")]
+    Xxh3,
+    #[cfg(feature = "sha256")]
+    Sha256,
+}
+
 pub struct PackageManager {
     pub package_dir: PathBuf,
-    pub package_paths: FxHashMap<u16, PackagePath>,
+    /// Additional package directories, see [PackageManagerBuilder::extra_packages_dir]
+    pub extra_package_dirs: Vec<PathBuf>,
+    pub package_paths: RwLock<FxHashMap<u16, PackagePath>>,
+    /// Paths of the non-english variants of each package, see [PackageManager::read_tag_localized]
+    pub localized_paths: RwLock<FxHashMap<u16, Vec<PackagePath>>>,
     pub version: GameVersion,
     pub platform: PackagePlatform,
 
+    /// Lookup tables of every indexed package.
+    /// [PackageManager::reindex] swaps these out as a whole, so readers never see a partially built index
+    pub lookup: RwLock<LookupTables>,
+
+    /// Packages that are currently open for reading
+    pkgs: RwLock<FxHashMap<u16, Arc<dyn Package>>>,
+    /// Language variants of packages that are currently open for reading
+    localized_pkgs: RwLock<FxHashMap<(u16, PackageLanguage), Arc<dyn Package>>>,
+
+    /// Maximum amount of threads used to read package tables
+    index_threads: usize,
+    on_progress: Option<ProgressCallback>,
+    cancel: Option<Arc<AtomicBool>>,
+    open_options: PackageOpenOptions,
+    /// Directory the package cache is stored in, next to the executable if `None`
+    #[cfg_attr(feature = "ignore_package_cache", allow(dead_code))]
+    cache_dir: Option<PathBuf>,
+    /// See [PackageManagerBuilder::shared_cache]
+    #[cfg_attr(feature = "ignore_package_cache", allow(dead_code))]
+    shared_cache: bool,
+    cache_status: CacheStatus,
+}
+
+/// Entries, hashes and named tags of every indexed package, see [PackageManager::lookup]
+#[derive(Default)]
+pub struct LookupTables {
     /// Every entry
-    pub package_entry_index: FxHashMap<u16, Vec<UEntryHeader>>,
+    pub package_entry_index: FxHashMap<u16, Arc<[UEntryHeader]>>,
     pub hash64_table: HashMap<u64, HashTableEntryShort>,
+    /// Reverse lookup for `hash64_table`.
+    /// A single tag can be referenced by more than one hash64, so every hash is kept
+    pub tag32_to_tag64: FxHashMap<TagHash, Vec<TagHash64>>,
     pub named_tags: Vec<PackageNamedTagEntry>,
+    /// Packages that couldn't be read during indexing, along with the error
+    failed_packages: Vec<PackageFailure>,
+}
 
+/// Snapshot of the memory held by a [PackageManager], see [PackageManager::cache_stats]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
     /// Packages that are currently open for reading
-    pkgs: RwLock<FxHashMap<u16, Arc<dyn Package>>>,
+    pub open_packages: usize,
+    /// Decompressed block data cached across all open packages
+    pub block_cache_bytes: usize,
+    /// Entries, hash64s and named tags in the lookup tables
+    pub index_entries: usize,
+    /// Approximate size of the lookup tables, not counting hashmap overhead
+    pub index_bytes: usize,
+}
+
+/// Size and amount of all entries of one (file type, file subtype) pair, see [PackageManager::type_statistics]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TypeStat {
+    pub file_type: u8,
+    pub file_subtype: u8,
+    pub total_bytes: u64,
+    pub count: usize,
+}
+
+/// Where a [PackageManager] got its package paths from during construction, see [PackageManager::cache_status]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheStatus {
+    /// Package paths were loaded from the package cache
+    LoadedFromCache,
+    /// Package paths were taken from a cache entry for the same install at a different location, see
+    /// [PackageManagerBuilder::fingerprint_cache]
+    Relocated,
+    /// There was no usable cache entry, so every package directory was scanned
+    Rebuilt,
+}
+
+/// Handle to a single open package, see [PackageManager::reader_for].
+/// Keeps the package alive and skips the manager's package lookup on every read, which adds up in bulk extraction loops
+#[derive(Clone)]
+pub struct PackageReader {
+    pkg_id: u16,
+    package: Arc<dyn Package>,
+}
+
+impl PackageReader {
+    pub fn pkg_id(&self) -> u16 {
+        self.pkg_id
+    }
+
+    pub fn package(&self) -> &Arc<dyn Package> {
+        &self.package
+    }
+
+    /// Reads the entire specified entry's data
+    pub fn read(&self, entry_index: usize) -> anyhow::Result<Vec<u8>> {
+        self.package.read_entry(entry_index)
+    }
+
+    /// Reads a tag, which needs to be in this package
+    pub fn read_tag(&self, tag: impl Into<TagHash>) -> anyhow::Result<Vec<u8>> {
+        let tag = tag.into();
+        ensure!(
+            tag.pkg_id() == self.pkg_id,
+            "Tag {tag} is not in package {:04x}",
+            self.pkg_id
+        );
+
+        self.read(tag.entry_index() as _)
+    }
+}
+
+/// Package ID and error message of a package that failed to open
+type PackageFailure = (u16, String);
+
+/// Called with the amount of packages processed so far and the total amount of packages
+pub type ProgressCallback = Box<dyn Fn(usize, usize) + Send + Sync>;
+
+pub struct PackageManagerBuilder {
+    packages_dir: PathBuf,
+    extra_dirs: Vec<PathBuf>,
+    version: GameVersion,
+    platform: Option<PackagePlatform>,
+    index_threads: usize,
+    on_progress: Option<ProgressCallback>,
+    cancel: Option<Arc<AtomicBool>>,
+    open_options: PackageOpenOptions,
+    defer_indexing: bool,
+    strict_version_check: bool,
+    strict_platform_check: bool,
+    fingerprint_cache: bool,
+    cache_dir: Option<PathBuf>,
+    shared_cache: bool,
+}
+
+impl PackageManagerBuilder {
+    /// Reading package tables is mostly I/O-bound, more threads than this tends to thrash slower disks
+    pub const DEFAULT_INDEX_THREADS: usize = 8;
+
+    pub fn platform(mut self, platform: PackagePlatform) -> Self {
+        self.platform = Some(platform);
+        self
+    }
+
+    /// Sets the maximum amount of threads used to read package tables.
+    /// Lower values can be faster on hard drives, as they avoid excessive seeking
+    pub fn index_threads(mut self, threads: usize) -> Self {
+        self.index_threads = threads.max(1);
+        self
+    }
+
+    /// Sets a callback that is invoked every time a package has been indexed, with the amount of packages done and
+    /// the total. Packages are indexed in parallel, so the callback can be called from multiple threads at once
+    pub fn on_progress(mut self, f: impl Fn(usize, usize) + Send + Sync + 'static) -> Self {
+        self.on_progress = Some(Box::new(f));
+        self
+    }
+
+    /// Sets a flag that aborts indexing with [PackageError::Cancelled] once it's set to `true`
+    pub fn cancellation_token(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Sets a secondary directory to look for patch files in when they're not next to the package being read
+    pub fn patch_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.open_options.patch_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Overrides how patch file paths are built from a package path, see [PackageOpenOptions::patch_naming]
+    pub fn patch_naming(mut self, naming: PatchNamingFn) -> Self {
+        self.open_options.patch_naming = Some(naming);
+        self
+    }
+
+    /// Ignores patch files newer than the given patch during discovery, and refuses to read blocks from them, eg. to
+    /// look at an install as it was before an update. See [PackageOpenOptions::max_patch].
+    /// The package cache isn't used when this is set
+    pub fn max_patch(mut self, patch: u16) -> Self {
+        self.open_options.max_patch = Some(patch);
+        self
+    }
+
+    /// Discovers and reads packages through the given source instead of the package directories, eg. a
+    /// [ZipSource](crate::source::ZipSource) for a zipped install. The package cache isn't used with a custom source
+    pub fn source(mut self, source: Arc<dyn PackageSource>) -> Self {
+        self.open_options.source = Some(source);
+        self
+    }
+
+    /// Adds another directory to discover packages in, for installs that split packages across multiple folders.
+    /// When a package ID shows up in more than one directory, the directory added last wins
+    pub fn extra_packages_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.extra_dirs.push(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Matches the package cache by [PackageManager::install_fingerprint] instead of the directory path, so moving an
+    /// install to a different location keeps using its existing cache
+    pub fn fingerprint_cache(mut self) -> Self {
+        self.fingerprint_cache = true;
+        self
+    }
+
+    /// Stores the package cache in the given directory instead of next to the executable.
+    /// Tools pointing at the same directory share their cache, it's replaced atomically so concurrent processes never
+    /// see a partially written file
+    pub fn cache_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.cache_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Stores the package cache in a per-user cache directory shared by every tool using this crate, matched by the
+    /// install fingerprint (see [Self::fingerprint_cache]), version and platform.
+    /// Falls back to the executable directory if the user cache directory can't be determined
+    pub fn shared_cache(mut self) -> Self {
+        self.cache_dir = shared_cache_dir();
+        self.fingerprint_cache = true;
+        self.shared_cache = true;
+        self
+    }
+
+    /// Fails with an error instead of logging a warning when the discovered packages don't look like they're from the
+    /// selected game version
+    pub fn strict_version_check(mut self) -> Self {
+        self.strict_version_check = true;
+        self
+    }
+
+    /// Fails with an error instead of logging a warning when the platform given with [Self::platform] doesn't match
+    /// the platform prefix of the discovered packages
+    pub fn strict_platform_check(mut self) -> Self {
+        self.strict_platform_check = true;
+        self
+    }
+
+    /// Skips building the lookup tables, so the manager can be shared before indexing.
+    /// Tag lookups won't resolve until the tables are built with [PackageManager::reindex] or
+    /// [PackageManager::build_lookup_tables]
+    pub fn defer_indexing(mut self) -> Self {
+        self.defer_indexing = true;
+        self
+    }
+
+    pub fn build(self) -> anyhow::Result<PackageManager> {
+        PackageManager::from_builder(self)
+    }
 }
 
 impl PackageManager {
@@ -52,50 +314,146 @@ impl PackageManager {
         version: GameVersion,
         platform: Option<PackagePlatform>,
     ) -> anyhow::Result<PackageManager> {
-        // All the latest packages
-        let mut packages: FxHashMap<u16, String> = Default::default();
+        let mut builder = Self::builder(packages_dir, version);
+        builder.platform = platform;
+        builder.build()
+    }
 
-        let oo2core_3_path = packages_dir.as_ref().join("../bin/x64/oo2core_3_win64.dll");
-        let oo2core_9_path = packages_dir.as_ref().join("../bin/x64/oo2core_9_win64.dll");
+    /// Creates a manager for packages spread across multiple directories.
+    /// When a package ID shows up in more than one directory, the last directory wins
+    pub fn new_multi(
+        dirs: &[PathBuf],
+        version: GameVersion,
+        platform: Option<PackagePlatform>,
+    ) -> anyhow::Result<PackageManager> {
+        let (first, rest) = dirs.split_first().context("No package directories given")?;
+        let mut builder = rest.iter().fold(Self::builder(first, version), |b, d| {
+            b.extra_packages_dir(d)
+        });
+        builder.platform = platform;
+        builder.build()
+    }
 
-        if oo2core_3_path.exists() {
-            let mut o = oodle::OODLE_3.write();
-            if o.is_none() {
-                *o = oodle::Oodle::from_path(oo2core_3_path).ok();
+    /// Returns every platform that has packages in the given directory, based on the platform prefix of their
+    /// filenames. Useful for letting the user pick a platform when a directory contains more than one
+    pub fn available_platforms<P: AsRef<Path>>(dir: P) -> anyhow::Result<Vec<PackagePlatform>> {
+        let dir = dir.as_ref();
+        let mut platforms = vec![];
+        for entry in
+            fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))?
+        {
+            let path = entry?.path();
+            if !path.is_file() || !path.to_string_lossy().to_lowercase().ends_with(".pkg") {
+                continue;
             }
-        }
 
-        if oo2core_9_path.exists() {
-            let mut o = oodle::OODLE_9.write();
-            if o.is_none() {
-                *o = oodle::Oodle::from_path(oo2core_9_path).ok();
+            let Some(platform) = PackagePath::parse(&path.to_string_lossy())
+                .and_then(|p| PackagePlatform::from_str(&p.platform).ok())
+            else {
+                continue;
+            };
+
+            if !platforms.contains(&platform) {
+                platforms.push(platform);
             }
         }
 
-        let build_new_cache = if let Some(cache) = Self::read_package_cache(false) {
+        platforms.sort_by_key(|p| {
+            <PackagePlatform as clap::ValueEnum>::value_variants()
+                .iter()
+                .position(|v| v == p)
+        });
+        Ok(platforms)
+    }
+
+    pub fn builder<P: AsRef<Path>>(packages_dir: P, version: GameVersion) -> PackageManagerBuilder {
+        PackageManagerBuilder {
+            packages_dir: packages_dir.as_ref().to_path_buf(),
+            extra_dirs: vec![],
+            version,
+            platform: None,
+            index_threads: PackageManagerBuilder::DEFAULT_INDEX_THREADS,
+            on_progress: None,
+            cancel: None,
+            open_options: Default::default(),
+            defer_indexing: false,
+            strict_version_check: false,
+            strict_platform_check: false,
+            fingerprint_cache: false,
+            cache_dir: None,
+            shared_cache: false,
+        }
+    }
+
+    fn from_builder(builder: PackageManagerBuilder) -> anyhow::Result<PackageManager> {
+        let PackageManagerBuilder {
+            packages_dir,
+            extra_dirs,
+            version,
+            platform,
+            index_threads,
+            on_progress,
+            cancel,
+            open_options,
+            defer_indexing,
+            strict_version_check,
+            strict_platform_check,
+            fingerprint_cache,
+            cache_dir,
+            shared_cache,
+        } = builder;
+
+        // All the latest packages
+        let mut packages: FxHashMap<u16, String> = Default::default();
+        // The latest non-english variants of each package
+        let mut localized: FxHashMap<u16, Vec<String>> = Default::default();
+
+        #[cfg(feature = "oodle")]
+        Self::discover_oodle(&packages_dir);
+
+        let use_cache = uses_package_cache(&open_options);
+        // Computed once here and stored with the cache entry, so a fingerprint cache can find it later
+        let fingerprint = if use_cache {
+            match InstallFingerprint::new(&packages_dir, &extra_dirs, &open_options) {
+                Ok(f) => Some(f),
+                Err(e) if fingerprint_cache => return Err(e),
+                Err(_) => None,
+            }
+        } else {
+            None
+        };
+
+        let mut relocated_cache = false;
+        let build_new_cache = if let Some(cache) = use_cache
+            .then(|| Self::read_package_cache(cache_dir.as_deref(), false))
+            .flatten()
+        {
             info!("Loading package cache");
-            if let Some(p) = cache.get_paths(version, platform, Some(packages_dir.as_ref()))? {
-                let timestamp = fs::metadata(&packages_dir)
-                    .ok()
-                    .and_then(|m| {
-                        Some(
-                            m.modified()
-                                .ok()?
-                                .duration_since(SystemTime::UNIX_EPOCH)
-                                .ok()?
-                                .as_secs(),
-                        )
-                    })
-                    .unwrap_or(0);
+            let relocated = fingerprint
+                .as_ref()
+                .filter(|_| fingerprint_cache)
+                .and_then(|f| f.relocate(cache.find_by_fingerprint(version, platform, f.hash)?));
+
+            if let Some((paths, localized_paths)) = relocated {
+                info!("Found a cache entry matching the install fingerprint");
+                packages = paths;
+                localized = localized_paths;
+                relocated_cache = true;
+                false
+            } else if let Some(p) =
+                cache.get_paths(version, platform, Some(packages_dir.as_path()), &extra_dirs)?
+            {
+                let timestamp = directories_timestamp(&packages_dir, &extra_dirs);
 
                 if p.timestamp < timestamp {
                     info!("Detected package directory changes, rebuilding cache");
                     true
-                } else if p.base_path != packages_dir.as_ref() {
+                } else if p.base_path != packages_dir || p.extra_paths != extra_dirs {
                     warn!("Package directory path changed, rebuilding cache");
                     true
                 } else {
                     packages = p.paths.clone();
+                    localized = p.localized_paths.clone();
                     false
                 }
             } else {
@@ -107,73 +465,279 @@ impl PackageManager {
 
         if build_new_cache {
             info!("Creating new package cache for {}", version.id());
-            let path = packages_dir.as_ref();
-            // Every package in the given directory, including every patch
-            let mut packages_all = vec![];
-            debug_span!("Discover packages in directory").in_scope(|| -> anyhow::Result<()> {
-                for entry in fs::read_dir(path)? {
-                    let entry = entry?;
-                    let path = entry.path();
-                    if path.is_file() && path.to_string_lossy().to_lowercase().ends_with(".pkg") {
-                        packages_all.push(path.to_string_lossy().to_string());
-                    }
-                }
+            (packages, localized) = Self::discover_packages(
+                version,
+                &packages_dir,
+                &extra_dirs,
+                &open_options,
+                cancel.as_deref(),
+            )?;
+        }
 
-                Ok(())
-            })?;
+        let (package_paths, localized_paths) = parse_package_paths(packages, localized);
 
-            packages_all.sort();
+        ensure!(!package_paths.is_empty(), "No packages found");
+        if let Some(message) =
+            Self::check_discovered_version(version, &package_paths, &open_options)
+        {
+            if strict_version_check {
+                anyhow::bail!(message);
+            }
 
-            debug_span!("Filter latest packages").in_scope(|| {
-                for p in packages_all {
-                    let parts: Vec<&str> = p.split('_').collect();
-                    if let Some(Ok(pkg_id)) = parts
-                        .get(parts.len() - 2)
-                        .map(|s| u16::from_str_radix(s, 16))
-                    {
-                        packages.insert(pkg_id, p);
-                    } else {
-                        let _span = debug_span!("Open package to find package ID").entered();
-                        // Take the long route and extract the package ID from the header
-                        if let Ok(pkg) = version.open(&p) {
-                            if pkg.language().english_or_none() {
-                                packages.insert(pkg.pkg_id(), p);
+            warn!("{message}");
+        }
+
+        if let Some(message) =
+            platform.and_then(|p| Self::check_discovered_platform(p, &package_paths))
+        {
+            if strict_platform_check {
+                anyhow::bail!(message);
+            }
+
+            warn!("{message}");
+        }
+
+        let platform = package_paths
+            .values()
+            .find_map(|p| PackagePlatform::from_str(&p.platform).ok())
+            .or(platform)
+            .or_else(|| {
+                package_paths.values().find_map(|p| {
+                    let mut header = [0u8; 4];
+                    open_options
+                        .open_file(&p.path)
+                        .and_then(|mut f| Ok(f.read_exact(&mut header)?))
+                        .and_then(|_| version::platform_from_header(&header, &p.path))
+                        .ok()
+                })
+            })
+            .context("Could not determine the package platform")?;
+
+        let cache_status = if relocated_cache {
+            CacheStatus::Relocated
+        } else if build_new_cache {
+            CacheStatus::Rebuilt
+        } else {
+            CacheStatus::LoadedFromCache
+        };
+
+        let s = Self {
+            package_dir: packages_dir,
+            extra_package_dirs: extra_dirs,
+            platform,
+            package_paths: RwLock::new(package_paths),
+            localized_paths: RwLock::new(localized_paths),
+            version,
+            lookup: Default::default(),
+            pkgs: Default::default(),
+            localized_pkgs: Default::default(),
+            index_threads,
+            on_progress,
+            cancel,
+            open_options,
+            cache_dir,
+            shared_cache,
+            cache_status,
+        };
+
+        if use_cache && (build_new_cache || relocated_cache) {
+            s.write_package_cache(fingerprint.map_or(0, |f| f.hash))
+                .ok();
+        }
+
+        if !defer_indexing {
+            s.build_lookup_tables()?;
+        }
+
+        Ok(s)
+    }
+
+    /// Finds the latest patch of every package in the package directories (or the custom source), returning the paths
+    /// of the english/language-less packages and the paths of the non-english variants of each package
+    #[allow(clippy::type_complexity)]
+    fn discover_packages(
+        version: GameVersion,
+        packages_dir: &Path,
+        extra_dirs: &[PathBuf],
+        open_options: &PackageOpenOptions,
+        cancel: Option<&AtomicBool>,
+    ) -> anyhow::Result<(FxHashMap<u16, String>, FxHashMap<u16, Vec<String>>)> {
+        // All the latest packages
+        let mut packages: FxHashMap<u16, String> = Default::default();
+        // The latest non-english variants of each package
+        let mut localized: FxHashMap<u16, Vec<String>> = Default::default();
+
+        // Every package in the given directories, including every patch.
+        // Each directory is sorted separately, so packages from later directories override earlier ones
+        let mut packages_all = vec![];
+        if let Some(source) = &open_options.source {
+            packages_all = source.list_packages()?;
+            packages_all.sort_by_cached_key(|p| PackagePath::parse_with_defaults(p));
+        } else {
+            for dir in std::iter::once(packages_dir).chain(extra_dirs.iter().map(PathBuf::as_path))
+            {
+                let mut dir_packages = vec![];
+                debug_span!("Discover packages in directory").in_scope(
+                    || -> anyhow::Result<()> {
+                        for entry in fs::read_dir(dir)
+                            .with_context(|| format!("Failed to read {}", dir.display()))?
+                        {
+                            check_cancelled(cancel)?;
+                            let entry = entry?;
+                            let path = entry.path();
+                            if path.is_file()
+                                && path.to_string_lossy().to_lowercase().ends_with(".pkg")
+                            {
+                                dir_packages.push(path.to_string_lossy().to_string());
                             }
                         }
+
+                        Ok(())
+                    },
+                )?;
+
+                // Sorted by patch number rather than by name, otherwise _10.pkg would come before _2.pkg
+                dir_packages.sort_by_cached_key(|p| PackagePath::parse_with_defaults(p));
+                packages_all.extend(dir_packages);
+            }
+        }
+
+        let mut localized_latest: FxHashMap<(u16, String), String> = Default::default();
+        debug_span!("Filter latest packages").in_scope(|| -> anyhow::Result<()> {
+            for p in packages_all {
+                check_cancelled(cancel)?;
+                let parsed = PackagePath::parse(&p);
+                if let (Some(max_patch), Some(pp)) = (open_options.max_patch, &parsed) {
+                    if pp.patch as u16 > max_patch {
+                        continue;
                     }
                 }
-            });
+
+                if let Some(pkg_id) = parsed.as_ref().and_then(PackagePath::numeric_id) {
+                    match parsed
+                        .filter(|pp| !pp.is_english())
+                        .and_then(|pp| pp.language)
+                    {
+                        Some(language) => {
+                            localized_latest.insert((pkg_id, language), p);
+                        }
+                        None => {
+                            packages.insert(pkg_id, p);
+                        }
+                    }
+                } else {
+                    let _span = debug_span!("Open package to find package ID").entered();
+                    // Take the long route and extract the package ID from the header
+                    if let Ok(pkg) = version.open_with_options(&p, open_options) {
+                        if pkg.language().english_or_none() {
+                            packages.insert(pkg.pkg_id().into(), p);
+                        }
+                    }
+                }
+            }
+
+            Ok(())
+        })?;
+
+        for ((pkg_id, _), p) in localized_latest.into_iter().sorted() {
+            localized.entry(pkg_id).or_default().push(p);
         }
 
-        let package_paths: FxHashMap<u16, PackagePath> = packages
-            .into_iter()
-            .map(|(id, p)| (id, PackagePath::parse_with_defaults(&p)))
-            .collect();
+        Ok((packages, localized))
+    }
 
-        let first_path = package_paths.values().next().context("No packages found")?;
+    /// Reads the header of one of the discovered packages, returning a warning message if it doesn't look like it's
+    /// from the given version. Picking the wrong version is an easy mistake that otherwise only shows up as every
+    /// package failing to open during indexing
+    fn check_discovered_version(
+        version: GameVersion,
+        package_paths: &FxHashMap<u16, PackagePath>,
+        options: &PackageOpenOptions,
+    ) -> Option<String> {
+        let (_, path) = package_paths.iter().min_by_key(|(id, _)| **id)?;
+        let mut header = [0u8; 2];
+        options
+            .open_file(&path.path)
+            .ok()?
+            .read_exact(&mut header)
+            .ok()?;
+
+        let candidates = version::probe_header(&header);
+        if candidates.is_empty() || candidates.contains(&version) {
+            return None;
+        }
 
-        let mut s = Self {
-            package_dir: packages_dir.as_ref().to_path_buf(),
-            platform: PackagePlatform::from_str(first_path.platform.as_str())?,
-            package_paths,
-            version,
-            package_entry_index: Default::default(),
-            hash64_table: Default::default(),
-            pkgs: Default::default(),
-            named_tags: Default::default(),
+        // Destiny 1 console packages are big endian
+        let header_version = if header[0] == 0 {
+            u16::from_be_bytes(header)
+        } else {
+            u16::from_le_bytes(header)
         };
 
-        if build_new_cache {
-            s.write_package_cache().ok();
+        Some(format!(
+            "You selected {} but the packages look like {} (header version {header_version})",
+            version.name(),
+            candidates.iter().map(|v| v.name()).join(" / ")
+        ))
+    }
+
+    /// Compares the requested platform against the platform prefixes of the discovered package filenames, returning a
+    /// warning message listing the platforms that were found if the requested one isn't among them.
+    /// The platform from the filenames is used either way, so the cache doesn't end up keyed under the wrong platform
+    fn check_discovered_platform(
+        platform: PackagePlatform,
+        package_paths: &FxHashMap<u16, PackagePath>,
+    ) -> Option<String> {
+        let discovered = package_paths
+            .values()
+            .filter_map(|p| PackagePlatform::from_str(&p.platform).ok())
+            .sorted_by_key(|p| {
+                <PackagePlatform as clap::ValueEnum>::value_variants()
+                    .iter()
+                    .position(|v| v == p)
+            })
+            .dedup()
+            .collect_vec();
+
+        if discovered.is_empty() || discovered.contains(&platform) {
+            return None;
         }
 
-        s.build_lookup_tables();
+        Some(format!(
+            "You selected platform {platform} but the packages are for {}",
+            discovered.iter().join(" / ")
+        ))
+    }
 
-        Ok(s)
+    /// Loads any Oodle versions that aren't loaded yet from the game's binary directory or the packages directory
+    #[cfg(feature = "oodle")]
+    fn discover_oodle(packages_dir: &Path) {
+        use crate::oodle::{self, OodleVersion};
+
+        let search_dirs = [packages_dir.join("../bin/x64"), packages_dir.to_path_buf()];
+        for version in [OodleVersion::V3, OodleVersion::V9] {
+            if oodle::is_loaded(version) {
+                continue;
+            }
+
+            let Some(lib_name) = oodle::library_name(version) else {
+                continue;
+            };
+            for path in search_dirs.iter().map(|d| d.join(&lib_name)) {
+                if !path.exists() {
+                    continue;
+                }
+
+                match oodle::preload(version, Some(&path)) {
+                    Ok(()) => break,
+                    Err(e) => warn!("Failed to load {}: {e:?}", path.display()),
+                }
+            }
+        }
     }
 
     #[cfg(feature = "ignore_package_cache")]
-    fn read_package_cache(silent: bool) -> Option<PathCache> {
+    fn read_package_cache(_cache_dir: Option<&Path>, silent: bool) -> Option<PathCache> {
         if !silent {
             warn!("Not loading tag cache: ignore_package_cache is enabled")
         }
@@ -181,16 +745,14 @@ impl PackageManager {
     }
 
     #[cfg(feature = "ignore_package_cache")]
-    fn write_package_cache(&self) -> anyhow::Result<()> {
+    fn write_package_cache(&self, _fingerprint: u64) -> anyhow::Result<()> {
         Ok(())
     }
 
     #[cfg(not(feature = "ignore_package_cache"))]
-    fn read_package_cache(silent: bool) -> Option<PathCache> {
-        let cache: Option<PathCache> = serde_json::from_reader(
-            std::fs::File::open(exe_relative_path("package_cache.json")).ok()?,
-        )
-        .ok();
+    fn read_package_cache(cache_dir: Option<&Path>, silent: bool) -> Option<PathCache> {
+        let cache: Option<PathCache> =
+            serde_json::from_reader(std::fs::File::open(package_cache_path(cache_dir)).ok()?).ok();
 
         if let Some(ref c) = cache {
             if c.cache_version != PathCache::VERSION {
@@ -204,203 +766,1130 @@ impl PackageManager {
         cache
     }
 
+    /// Stores the package paths in the package cache, along with the [PackageManager::install_fingerprint] of the
+    /// install
     #[cfg(not(feature = "ignore_package_cache"))]
-    fn write_package_cache(&self) -> anyhow::Result<()> {
-        let mut cache = Self::read_package_cache(true).unwrap_or_default();
-
-        let timestamp = fs::metadata(&self.package_dir)
-            .ok()
-            .and_then(|m| {
-                Some(
-                    m.modified()
-                        .ok()?
-                        .duration_since(SystemTime::UNIX_EPOCH)
-                        .ok()?
-                        .as_secs(),
-                )
-            })
-            .unwrap_or(0);
+    fn write_package_cache(&self, fingerprint: u64) -> anyhow::Result<()> {
+        let path = package_cache_path(self.cache_dir.as_deref());
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
 
-        let entry = cache
-            .versions
-            .entry(self.cache_key())
-            .or_insert_with(|| PathCacheEntry {
-                timestamp,
-                version: self.version,
-                platform: self.platform,
-                base_path: self.package_dir.clone(),
-                paths: Default::default(),
+        // Other processes sharing the cache do the same read-modify-write, hold an exclusive lock for all of it so
+        // their entries aren't lost. The lock is released when the file is dropped
+        let lock = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path.with_extension("json.lock"))?;
+        lock.lock()?;
+
+        // Re-read the cache under the lock, so entries written by other processes in the meantime are kept
+        let mut cache =
+            Self::read_package_cache(self.cache_dir.as_deref(), true).unwrap_or_default();
+
+        let timestamp = directories_timestamp(&self.package_dir, &self.extra_package_dirs);
+
+        let key = if self.shared_cache {
+            // A shared cache can hold multiple installs of the same version, so they're told apart by their
+            // fingerprint. Entries left behind by an older state of this install are dropped
+            cache.versions.retain(|_, v| {
+                !(v.version == self.version
+                    && v.platform == self.platform
+                    && v.base_path == self.package_dir
+                    && v.extra_paths == self.extra_package_dirs)
             });
+            format!("{}_{fingerprint:016x}", self.cache_key())
+        } else {
+            self.cache_key()
+        };
+
+        let entry = cache.versions.entry(key).or_insert_with(|| PathCacheEntry {
+            timestamp,
+            version: self.version,
+            platform: self.platform,
+            base_path: self.package_dir.clone(),
+            extra_paths: self.extra_package_dirs.clone(),
+            fingerprint: 0,
+            paths: Default::default(),
+            localized_paths: Default::default(),
+        });
 
         entry.timestamp = timestamp;
         entry.base_path = self.package_dir.clone();
+        entry.extra_paths = self.extra_package_dirs.clone();
+        entry.fingerprint = fingerprint;
         entry.paths.clear();
 
-        for (id, path) in &self.package_paths {
+        for (id, path) in self.package_paths.read().iter() {
             entry.paths.insert(*id, path.path.clone());
         }
 
-        Ok(std::fs::write(
-            exe_relative_path("package_cache.json"),
-            serde_json::to_string_pretty(&cache)?,
-        )?)
+        entry.localized_paths = self
+            .localized_paths
+            .read()
+            .iter()
+            .map(|(id, paths)| (*id, paths.iter().map(|p| p.path.clone()).collect()))
+            .collect();
+
+        // Write to a temporary file first and move it into place, so readers (which don't take the lock) never see a
+        // partially written file
+        let temp_path = path.with_extension(format!("json.{}.tmp", std::process::id()));
+        fs::write(&temp_path, serde_json::to_string_pretty(&cache)?)?;
+        fs::rename(&temp_path, &path).inspect_err(|_| {
+            fs::remove_file(&temp_path).ok();
+        })?;
+
+        Ok(())
     }
 
     /// Generates a key unique to the game version + platform combination
-    /// eg. GameVersion::DestinyTheTakenKing and PackagePlatform::PS4 generates cache key "d1_ttk_ps4"
+    /// eg. GameVersion::DestinyTheTakenKing and PackagePlatform::PS4 generates cache key "d1_ttk_ps4".
+    /// Managers with extra package directories get a hash of the directory set appended to the key.
+    ///
+    /// The platform is the one found in the package filenames, not the one requested through
+    /// [PackageManagerBuilder::platform]
     pub fn cache_key(&self) -> String {
-        format!("{}_{}", self.version.id(), self.platform)
+        cache_key(self.version, self.platform, &self.extra_package_dirs)
     }
 
-    pub fn build_lookup_tables(&mut self) {
-        let tables: Vec<_> = self
-            .package_paths
-            .par_iter()
-            .filter_map(|(_, p)| {
-                let _span = debug_span!("Read package tables", package = p.path).entered();
-                let pkg = match self.version.open(&p.path) {
-                    Ok(package) => package,
-                    Err(e) => {
-                        error!("Failed to open package '{}': {e}", p.filename);
+    /// Whether the package paths were loaded from the package cache or had to be rediscovered when this manager was
+    /// created. A rebuild means every package directory was scanned, which is the slow part of a first run
+    pub fn cache_status(&self) -> CacheStatus {
+        self.cache_status
+    }
+
+    /// Rebuilds all lookup tables from scratch, for the packages that are currently known to the manager.
+    /// If indexing gets cancelled, the existing tables are left untouched.
+    ///
+    /// Packages are always indexed under the ID from their header, as that's the ID their tags use. When a package's
+    /// filename has a different ID, a warning is logged and its path is moved to the header ID in `package_paths`
+    pub fn build_lookup_tables(&self) -> anyhow::Result<()> {
+        let package_paths = self.package_paths.read().clone();
+        let (tables, failed) = self.read_all_package_tables(&package_paths)?;
+        rekey_by_header_id(&mut self.package_paths.write(), &tables);
+        *self.lookup.write() = LookupTables::new(tables, failed);
+
+        Ok(())
+    }
+
+    /// Discovers the packages in the package directories again and rebuilds all lookup tables, picking up packages
+    /// and patches that were added or removed since the manager was created.
+    /// Packages are read without holding any locks, so other threads can keep using the existing tables until the new
+    /// ones are swapped in. If indexing gets cancelled, the existing paths and tables are left untouched.
+    /// Open packages are closed so they're reopened from their latest patch
+    pub fn reindex(&self) -> anyhow::Result<()> {
+        let (packages, localized) = Self::discover_packages(
+            self.version,
+            &self.package_dir,
+            &self.extra_package_dirs,
+            &self.open_options,
+            self.cancel.as_deref(),
+        )?;
+        let (mut package_paths, localized_paths) = parse_package_paths(packages, localized);
+        let (tables, failed) = self.read_all_package_tables(&package_paths)?;
+        rekey_by_header_id(&mut package_paths, &tables);
+
+        *self.package_paths.write() = package_paths;
+        *self.localized_paths.write() = localized_paths;
+        *self.lookup.write() = LookupTables::new(tables, failed);
+        self.pkgs.write().clear();
+        self.localized_pkgs.write().clear();
+
+        if uses_package_cache(&self.open_options) {
+            self.write_package_cache(self.install_fingerprint()).ok();
+        }
+
+        Ok(())
+    }
+
+    /// Reads the tables of every given package, returning the tables and the packages that failed to open
+    fn read_all_package_tables(
+        &self,
+        package_paths: &FxHashMap<u16, PackagePath>,
+    ) -> anyhow::Result<(Vec<PackageTables>, Vec<PackageFailure>)> {
+        let total = package_paths.len();
+        let done = AtomicUsize::new(0);
+        let read_tables = || -> (Vec<_>, Vec<_>) {
+            package_paths
+                .par_iter()
+                .filter_map(|(pkg_id, p)| {
+                    if self.is_cancelled() {
                         return None;
                     }
+
+                    let tables =
+                        Self::read_package_tables(self.version, &self.open_options, *pkg_id, p);
+                    if let Some(on_progress) = &self.on_progress {
+                        on_progress(done.fetch_add(1, Ordering::Relaxed) + 1, total);
+                    }
+
+                    Some(tables.map_err(|e| {
+                        error!("Failed to open package '{}': {e}", p.filename);
+                        (*pkg_id, format!("{e:#}"))
+                    }))
+                })
+                .partition_map(|r| match r {
+                    Ok(t) => rayon::iter::Either::Left(t),
+                    Err(f) => rayon::iter::Either::Right(f),
+                })
+        };
+
+        let (tables, mut failed) = match rayon::ThreadPoolBuilder::new()
+            .num_threads(self.index_threads)
+            .build()
+        {
+            Ok(pool) => pool.install(read_tables),
+            Err(e) => {
+                warn!("Failed to create indexing thread pool, using the global pool: {e}");
+                read_tables()
+            }
+        };
+
+        check_cancelled(self.cancel.as_deref())?;
+
+        failed.sort();
+        Ok((tables, failed))
+    }
+
+    /// Packages that couldn't be read during indexing, sorted by package ID, along with the error message.
+    /// Tags from these packages won't resolve
+    pub fn failed_packages(&self) -> Vec<(u16, String)> {
+        self.lookup.read().failed_packages.clone()
+    }
+
+    fn is_cancelled(&self) -> bool {
+        check_cancelled(self.cancel.as_deref()).is_err()
+    }
+
+    /// (Re-)indexes a single package, replacing any entries, hashes and named tags that were previously indexed for it.
+    /// The package needs to be registered in `package_paths`. If the ID in its header differs from `pkg_id`, the
+    /// package is indexed under the header ID instead, see [PackageManager::build_lookup_tables]
+    pub fn index_package(&self, pkg_id: u16) -> anyhow::Result<()> {
+        let _span = debug_span!("PackageManager::index_package", pkg_id).entered();
+        let path = self
+            .package_paths
+            .read()
+            .get(&pkg_id)
+            .cloned()
+            .with_context(|| format!("Couldn't get a path for package id {pkg_id:04x}"))?;
+
+        let tables =
+            match Self::read_package_tables(self.version, &self.open_options, pkg_id, &path) {
+                Ok(t) => t,
+                Err(e) => {
+                    let e = e.context(format!("Failed to open package '{}'", path.filename));
+                    let mut lookup = self.lookup.write();
+                    lookup.failed_packages.retain(|(id, _)| *id != pkg_id);
+                    lookup.failed_packages.push((pkg_id, format!("{e:#}")));
+                    lookup.failed_packages.sort();
+                    return Err(e);
+                }
+            };
+
+        rekey_by_header_id(
+            &mut self.package_paths.write(),
+            std::slice::from_ref(&tables),
+        );
+
+        let header_id = tables.pkg_id;
+        let mut lookup = self.lookup.write();
+        lookup.remove_package(pkg_id);
+        lookup.remove_package(header_id);
+        lookup.insert_package_tables(tables);
+        for (_, hashes) in lookup
+            .tag32_to_tag64
+            .iter_mut()
+            .filter(|(t, _)| t.pkg_id() == header_id)
+        {
+            hashes.sort();
+        }
+        drop(lookup);
+
+        let mut pkgs = self.pkgs.write();
+        pkgs.remove(&pkg_id);
+        pkgs.remove(&header_id);
+        Ok(())
+    }
+
+    /// Removes all entries, hashes and named tags belonging to the given package from the lookup tables.
+    /// Also closes the package if it's currently open
+    pub fn unindex_package(&self, pkg_id: u16) {
+        self.lookup.write().remove_package(pkg_id);
+        self.pkgs.write().remove(&pkg_id);
+    }
+
+    /// Opens a package with the manager's open options, so tables are read from the same source (and with the same
+    /// patch resolution and keys) as the package's data later on
+    fn read_package_tables(
+        version: GameVersion,
+        options: &PackageOpenOptions,
+        path_id: u16,
+        path: &PackagePath,
+    ) -> anyhow::Result<PackageTables> {
+        let _span = debug_span!("Read package tables", package = path.path).entered();
+        let pkg = version.open_with_options(&path.path, options)?;
+        if pkg.entries().len() > MAX_ENTRIES_PER_PACKAGE {
+            warn!(
+                "Package {:04x} has {} entries, tags past index {} will alias",
+                pkg.pkg_id(),
+                pkg.entries().len(),
+                MAX_ENTRIES_PER_PACKAGE - 1
+            );
+        }
+
+        let hashes = pkg
+            .hash64_table()
+            .iter()
+            .map(|h| {
+                (
+                    h.hash64,
+                    HashTableEntryShort {
+                        hash32: h.hash32,
+                        reference: h.reference,
+                    },
+                )
+            })
+            .collect();
+
+        Ok(PackageTables {
+            pkg_id: pkg.pkg_id().into(),
+            path_id,
+            entries: pkg.entries_arc().unwrap_or_else(|| pkg.entries().into()),
+            hashes,
+            named_tags: pkg.named_tags(),
+        })
+    }
+}
+
+impl LookupTables {
+    fn new(tables: Vec<PackageTables>, failed_packages: Vec<PackageFailure>) -> Self {
+        let mut lookup = LookupTables {
+            failed_packages,
+            ..Default::default()
+        };
+        for t in tables {
+            lookup.insert_package_tables(t);
+        }
+
+        for hashes in lookup.tag32_to_tag64.values_mut() {
+            hashes.sort();
+        }
+
+        info!("Loaded {} packages", lookup.package_entry_index.len());
+        lookup
+    }
+
+    /// Merges a package's tables into the lookup tables.
+    /// Note that this does not sort the hashes in `tag32_to_tag64`
+    fn insert_package_tables(&mut self, tables: PackageTables) {
+        for (hash64, e) in &tables.hashes {
+            self.tag32_to_tag64
+                .entry(e.hash32)
+                .or_default()
+                .push(TagHash64(*hash64));
+        }
+
+        self.package_entry_index
+            .insert(tables.pkg_id, tables.entries);
+        self.hash64_table.extend(tables.hashes);
+        self.named_tags.extend(tables.named_tags);
+    }
+
+    /// Removes all entries, hashes and named tags belonging to the given package
+    fn remove_package(&mut self, pkg_id: u16) {
+        self.package_entry_index.remove(&pkg_id);
+        self.failed_packages.retain(|(id, _)| *id != pkg_id);
+        self.hash64_table.retain(|_, e| e.hash32.pkg_id() != pkg_id);
+        self.tag32_to_tag64.retain(|t, _| t.pkg_id() != pkg_id);
+        self.named_tags.retain(|n| n.hash.pkg_id() != pkg_id);
+    }
+}
+
+/// Lookup table data read from a single package
+struct PackageTables {
+    /// ID from the package header
+    pkg_id: u16,
+    /// ID the package is registered under in `package_paths`
+    path_id: u16,
+    entries: Arc<[UEntryHeader]>,
+    hashes: Vec<(u64, HashTableEntryShort)>,
+    named_tags: Vec<PackageNamedTagEntry>,
+}
+
+impl PackageManager {
+    /// Returns the IDs of all packages with more entries than a [TagHash] can address.
+    /// Tags read from these packages may resolve to the wrong entry
+    pub fn oversized_packages(&self) -> Vec<u16> {
+        self.lookup
+            .read()
+            .package_entry_index
+            .iter()
+            .filter(|(_, e)| e.len() > MAX_ENTRIES_PER_PACKAGE)
+            .map(|(id, _)| *id)
+            .sorted()
+            .collect()
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        let entries = self.total_entry_count();
+        let lookup = self.lookup.read();
+        let pkgs = self.pkgs.read();
+        let named_tag_names: usize = lookup.named_tags.iter().map(|n| n.name.len()).sum();
+
+        CacheStats {
+            open_packages: pkgs.len(),
+            block_cache_bytes: pkgs.values().map(|p| p.block_cache_bytes()).sum(),
+            index_entries: entries + lookup.hash64_table.len() + lookup.named_tags.len(),
+            index_bytes: entries * std::mem::size_of::<UEntryHeader>()
+                + lookup.hash64_table.len() * std::mem::size_of::<(u64, HashTableEntryShort)>()
+                + lookup.tag32_to_tag64.values().map(Vec::len).sum::<usize>()
+                    * std::mem::size_of::<TagHash64>()
+                + lookup.named_tags.len() * std::mem::size_of::<PackageNamedTagEntry>()
+                + named_tag_names,
+        }
+    }
+
+    /// Checks every package for missing patch files, see [Package::validate_patches].
+    /// Returns the packages that failed to open or validate, sorted by package ID
+    pub fn validate_install(&self) -> Vec<(u16, anyhow::Error)> {
+        self.package_paths
+            .read()
+            .par_iter()
+            .filter_map(|(&pkg_id, path)| {
+                self.version
+                    .open_with_options(&path.path, &self.open_options)
+                    .and_then(|pkg| pkg.validate_patches())
+                    .err()
+                    .map(|e| (pkg_id, e))
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .sorted_by_key(|(pkg_id, _)| *pkg_id)
+            .collect()
+    }
+
+    /// Number of entries across every indexed package
+    pub fn total_entry_count(&self) -> usize {
+        self.lookup
+            .read()
+            .package_entry_index
+            .values()
+            .map(|e| e.len())
+            .sum()
+    }
+
+    /// Number of discovered packages, including ones that haven't been indexed (yet)
+    pub fn total_package_count(&self) -> usize {
+        self.package_paths.read().len()
+    }
+
+    /// Total on-disk size of every package, including all of their patch files
+    pub fn total_install_size(&self) -> u64 {
+        self.package_paths
+            .read()
+            .values()
+            .map(|p| {
+                let size = self.open_options.file_size(&p.path).unwrap_or(0);
+                let Ok(path_base) = package_path_base(&p.path) else {
+                    return size;
                 };
-                let entries = (pkg.pkg_id(), pkg.entries().to_vec());
 
-                let hashes = pkg
-                    .hash64_table()
-                    .iter()
-                    .map(|h| {
-                        (
-                            h.hash64,
-                            HashTableEntryShort {
-                                hash32: h.hash32,
-                                reference: h.reference,
-                            },
-                        )
+                // Older patches are resolved the same way they are when reading blocks from them
+                size + (0..p.patch)
+                    .filter_map(|patch| {
+                        self.open_options
+                            .patch_file_size(&path_base, patch as u16)
+                            .ok()
                     })
-                    .collect::<Vec<(u64, HashTableEntryShort)>>();
+                    .sum::<u64>()
+            })
+            .sum()
+    }
+
+    /// Hash identifying the content of this install, independent of where it's located.
+    /// Computed from the ID, patch number and file size of every package file without opening any of them, so it changes
+    /// whenever a patch is added or a package file changes size. The package cache is matched by the same hash.
+    /// Returns 0 if the package files can't be listed
+    pub fn install_fingerprint(&self) -> u64 {
+        InstallFingerprint::new(
+            &self.package_dir,
+            &self.extra_package_dirs,
+            &self.open_options,
+        )
+        .map_or(0, |f| f.hash)
+    }
+
+    /// Iterates over every indexed tag, ordered by package ID and entry index.
+    /// Iterates over a snapshot of the index, so it's unaffected by packages being (re-)indexed in the meantime
+    pub fn iter_tags(&self) -> impl Iterator<Item = (TagHash, UEntryHeader)> {
+        let packages = self
+            .lookup
+            .read()
+            .package_entry_index
+            .iter()
+            .map(|(p, e)| (*p, Arc::clone(e)))
+            .sorted_by_key(|(p, _)| *p)
+            .collect_vec();
+
+        packages.into_iter().flat_map(move |(p, e)| {
+            (0..e.len()).map(move |i| (TagHash::new(p, i as _), e[i].clone()))
+        })
+    }
+
+    /// Writes the metadata of every indexed entry as CSV
+    pub fn export_entries_csv(&self, mut writer: impl Write) -> anyhow::Result<()> {
+        writeln!(
+            writer,
+            "pkg_id,entry_index,tag_hash,reference,file_type,file_subtype,file_size"
+        )?;
+
+        for (tag, e) in self.iter_tags() {
+            writeln!(
+                writer,
+                "{:04x},{},{tag},{},{},{},{}",
+                tag.pkg_id(),
+                tag.entry_index(),
+                TagHash(e.reference),
+                e.file_type,
+                e.file_subtype,
+                e.file_size
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub fn get_all_by_reference(&self, reference: u32) -> Vec<(TagHash, UEntryHeader)> {
+        self.lookup
+            .read()
+            .package_entry_index
+            .par_iter()
+            .map(|(p, e)| {
+                e.iter()
+                    .enumerate()
+                    .filter(|(_, e)| e.reference == reference)
+                    .map(|(i, e)| (TagHash::new(*p, i as _), e.clone()))
+                    .collect::<Vec<(TagHash, UEntryHeader)>>()
+            })
+            .flatten()
+            .collect()
+    }
+
+    /// Reads every tag with the given reference, opening each package only once.
+    /// All of the data is buffered in memory, so this can get very large for common references
+    pub fn read_all_by_reference(&self, reference: u32) -> Vec<(TagHash, anyhow::Result<Vec<u8>>)> {
+        let by_package = self
+            .get_all_by_reference(reference)
+            .into_iter()
+            .map(|(t, _)| t)
+            .sorted()
+            .chunk_by(|t| t.pkg_id())
+            .into_iter()
+            .map(|(pkg_id, tags)| (pkg_id, tags.collect_vec()))
+            .collect_vec();
+
+        by_package
+            .into_par_iter()
+            .flat_map_iter(|(pkg_id, tags)| {
+                let pkg = self.get_or_load_pkg(pkg_id);
+                tags.into_iter().map(move |t| {
+                    let data = match &pkg {
+                        Ok(pkg) => pkg.read_entry(t.entry_index() as _),
+                        Err(e) => Err(anyhow::anyhow!("{e:?}")),
+                    };
+
+                    (t, data)
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the number of entries and their total size in bytes for every reference.
+    /// Only entries of the given file types are counted, or tag/tagglobal entries (types 8 and 16) when `None`
+    pub fn reference_histogram(&self, file_types: Option<&[u8]>) -> FxHashMap<u32, (usize, usize)> {
+        let file_types = file_types.unwrap_or(&[8, 16]);
+        let mut references: FxHashMap<u32, (usize, usize)> = Default::default();
+        let lookup = self.lookup.read();
+        for entry in lookup.package_entry_index.values().flat_map(|e| e.iter()) {
+            if file_types.contains(&entry.file_type) {
+                let e = references.entry(entry.reference).or_default();
+                e.0 += 1;
+                e.1 += entry.file_size as usize;
+            }
+        }
+
+        references
+    }
+
+    /// Returns every distinct (file type, file subtype) pair in the index along with the amount of entries using it,
+    /// most common first
+    pub fn present_entry_types(&self) -> Vec<(u8, u8, usize)> {
+        let mut counts: FxHashMap<(u8, u8), usize> = Default::default();
+        let lookup = self.lookup.read();
+        for entry in lookup.package_entry_index.values().flat_map(|e| e.iter()) {
+            *counts
+                .entry((entry.file_type, entry.file_subtype))
+                .or_default() += 1;
+        }
+
+        counts
+            .into_iter()
+            .map(|((t, s), count)| (t, s, count))
+            .sorted_by_key(|&(t, s, count)| (std::cmp::Reverse(count), t, s))
+            .collect()
+    }
+
+    /// Total size and amount of entries for every (file type, file subtype) pair in the index, largest first
+    pub fn type_statistics(&self) -> Vec<TypeStat> {
+        let mut totals: FxHashMap<(u8, u8), (u64, usize)> = Default::default();
+        let lookup = self.lookup.read();
+        for entry in lookup.package_entry_index.values().flat_map(|e| e.iter()) {
+            let e = totals
+                .entry((entry.file_type, entry.file_subtype))
+                .or_default();
+            e.0 += entry.file_size as u64;
+            e.1 += 1;
+        }
+
+        totals
+            .into_iter()
+            .map(
+                |((file_type, file_subtype), (total_bytes, count))| TypeStat {
+                    file_type,
+                    file_subtype,
+                    total_bytes,
+                    count,
+                },
+            )
+            .sorted_by_key(|s| {
+                (
+                    std::cmp::Reverse(s.total_bytes),
+                    s.file_type,
+                    s.file_subtype,
+                )
+            })
+            .collect()
+    }
+
+    /// Lazily reads every tag of the given type, one at a time.
+    /// Tags are read in package order, and packages that aren't already open are only kept open while their tags are
+    /// being read, so memory use stays bounded no matter how many tags match
+    pub fn stream_by_type(
+        &self,
+        etype: u8,
+        esubtype: Option<u8>,
+    ) -> impl Iterator<Item = anyhow::Result<(TagHash, Vec<u8>)>> + '_ {
+        let tags = self
+            .get_all_by_type(etype, esubtype)
+            .into_iter()
+            .map(|(t, _)| t)
+            .sorted_by_key(|t| (t.pkg_id(), t.entry_index()));
+
+        let mut current: Option<(u16, anyhow::Result<Arc<dyn Package>>)> = None;
+        tags.map(move |t| {
+            if current
+                .as_ref()
+                .is_some_and(|(pkg_id, _)| *pkg_id != t.pkg_id())
+            {
+                current = None;
+            }
+
+            let (_, pkg) = current
+                .get_or_insert_with(|| (t.pkg_id(), self.get_or_open_pkg_uncached(t.pkg_id())));
+            let pkg = pkg.as_ref().map_err(|e| anyhow::anyhow!("{e:?}"))?;
+
+            pkg.read_entry(t.entry_index() as _).map(|data| (t, data))
+        })
+    }
+
+    pub fn get_all_by_type(&self, etype: u8, esubtype: Option<u8>) -> Vec<(TagHash, UEntryHeader)> {
+        self.lookup
+            .read()
+            .package_entry_index
+            .par_iter()
+            .map(|(p, e)| {
+                e.iter()
+                    .enumerate()
+                    .filter(|(_, e)| {
+                        e.file_type == etype
+                            && esubtype.map(|t| t == e.file_subtype).unwrap_or(true)
+                    })
+                    .map(|(i, e)| (TagHash::new(*p, i as _), e.clone()))
+                    .collect::<Vec<(TagHash, UEntryHeader)>>()
+            })
+            .flatten()
+            .collect()
+    }
+
+    fn get_or_load_pkg(&self, pkg_id: u16) -> anyhow::Result<Arc<dyn Package>> {
+        let _span = tracing::debug_span!("PackageManager::get_or_Load_pkg", pkg_id).entered();
+        let v = self.pkgs.read();
+        if let Some(pkg) = v.get(&pkg_id) {
+            Ok(Arc::clone(pkg))
+        } else {
+            drop(v);
+            let package = self.open_pkg(pkg_id)?;
+            self.pkgs.write().insert(pkg_id, Arc::clone(&package));
+            Ok(package)
+        }
+    }
+
+    /// Returns the package if it's already open, otherwise opens it without keeping it open in the manager
+    fn get_or_open_pkg_uncached(&self, pkg_id: u16) -> anyhow::Result<Arc<dyn Package>> {
+        if let Some(pkg) = self.pkgs.read().get(&pkg_id) {
+            return Ok(Arc::clone(pkg));
+        }
+
+        self.open_pkg(pkg_id)
+    }
+
+    fn open_pkg(&self, pkg_id: u16) -> anyhow::Result<Arc<dyn Package>> {
+        let package_path = self
+            .package_paths
+            .read()
+            .get(&pkg_id)
+            .cloned()
+            .with_context(|| format!("Couldn't get a path for package id {pkg_id:04x}"))?;
+
+        self.version
+            .open_with_options(&package_path.path, &self.open_options)
+            .with_context(|| format!("Failed to open package '{}'", package_path.filename))
+    }
+
+    /// Closes a package, freeing its block cache and file handles.
+    /// Anything still holding an `Arc<dyn Package>` for it keeps the package alive until it's dropped.
+    /// The package will be reopened the next time one of its tags is read
+    pub fn close_package(&self, pkg_id: u16) {
+        self.pkgs.write().remove(&pkg_id);
+        self.localized_pkgs
+            .write()
+            .retain(|(id, _), _| *id != pkg_id);
+    }
+
+    /// Opens a package that is not part of the package directory, using the manager's game version.
+    /// The package is not registered with the manager, so its tags won't resolve through the lookup tables
+    pub fn read_loose_package(&self, path: &str) -> anyhow::Result<Arc<dyn Package>> {
+        let _span = tracing::debug_span!("PackageManager::read_loose_package", path).entered();
+        self.version
+            .open_with_options(path, &self.open_options)
+            .with_context(|| format!("Failed to open loose package '{path}'"))
+    }
+
+    /// Writes a package and all of its patches into a single self-contained package file.
+    /// The flattened package is read back afterwards to verify every entry matches the original
+    pub fn flatten_package(&self, pkg_id: u16, out_path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let _span = tracing::debug_span!("PackageManager::flatten_package", pkg_id).entered();
+        let out_path = out_path.as_ref();
+        let pkg = self.get_or_load_pkg(pkg_id)?;
+
+        let mut writer = PackageWriter::new(self.version, self.platform, pkg_id)?;
+        writer.patch_id = pkg.patch_id().into();
+        for (i, e) in pkg.entries().iter().enumerate() {
+            let data = pkg
+                .read_entry(i)
+                .with_context(|| format!("Failed to read entry {pkg_id:04x}/{i}"))?;
+            writer.add_entry(e.reference, e.file_type, e.file_subtype, data)?;
+        }
+
+        for n in pkg.named_tags() {
+            writer.add_named_tag(n);
+        }
+
+        for h in pkg.hash64_table() {
+            writer.add_hash64(h.hash64, h.hash32, h.reference);
+        }
+
+        writer.write(std::io::BufWriter::new(fs::File::create(out_path)?))?;
+
+        let flattened = self
+            .version
+            .open(&out_path.to_string_lossy())
+            .context("Failed to open flattened package")?;
+        ensure!(
+            flattened.entries().len() == pkg.entries().len(),
+            "Flattened package has {} entries, expected {}",
+            flattened.entries().len(),
+            pkg.entries().len()
+        );
+        for i in 0..pkg.entries().len() {
+            ensure!(
+                flattened.read_entry(i)? == pkg.read_entry(i)?,
+                "Entry {pkg_id:04x}/{i} differs after flattening"
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Reads a block from a package as it is stored on disk, without decrypting or decompressing it
+    pub fn read_raw_block(&self, pkg_id: u16, block_index: usize) -> anyhow::Result<Vec<u8>> {
+        self.get_or_load_pkg(pkg_id)?
+            .get_block_raw_bytes(block_index)
+    }
+
+    /// Checks whether every block in a package can be decrypted with the keys that are currently loaded.
+    /// Returns false if the package can't be opened
+    pub fn can_decrypt(&self, pkg_id: u16) -> bool {
+        let Ok(pkg) = self.get_or_load_pkg(pkg_id) else {
+            return false;
+        };
+
+        // Blocks with flag 0x8 are encrypted with a key specific to the package group
+        let needs_extra_key = (0..)
+            .map_while(|i| pkg.block_info(i))
+            .any(|b| (b.flags & 0x8) != 0);
+
+        !needs_extra_key
+            || pkg
+                .group_id()
+                .is_some_and(|g| self.open_options.keys.contains(g) || crypto::has_key_for_group(g))
+    }
+
+    /// Keys registered through [PackageManager::register_key], for decrypting blocks read with
+    /// [PackageManager::read_raw_block] through [crypto::try_decrypt_block]
+    pub fn keys(&self) -> &PackageKeys {
+        &self.open_options.keys
+    }
+
+    /// Registers a key for a package group, only used by packages opened through this manager.
+    /// Takes priority over keys loaded from `keys.txt`, so installs whose group IDs collide don't share keys.
+    /// Open packages from the group are closed so they pick up the new key when they're read next
+    pub fn register_key(&self, group: u64, key: [u8; 16], iv: [u8; 12]) {
+        self.open_options.keys.insert(group, key, iv);
+        self.pkgs
+            .write()
+            .retain(|_, pkg| pkg.group_id() != Some(group));
+        self.localized_pkgs
+            .write()
+            .retain(|_, pkg| pkg.group_id() != Some(group));
+    }
+
+    pub fn read_tag(&self, tag: impl Into<TagHash>) -> anyhow::Result<Vec<u8>> {
+        let _span = tracing::debug_span!("PackageManager::read_tag").entered();
+        let tag = tag.into();
+        self.get_or_load_pkg(tag.pkg_id())?
+            .read_entry(tag.entry_index() as _)
+    }
+
+    /// Reads a tag straight from disk, skipping the block cache of its package.
+    /// Every block of the tag is decrypted and decompressed again, so only use this when cached data might be stale
+    pub fn read_tag_uncached(&self, tag: impl Into<TagHash>) -> anyhow::Result<Vec<u8>> {
+        let _span = tracing::debug_span!("PackageManager::read_tag_uncached").entered();
+        let tag = tag.into();
+        self.get_or_load_pkg(tag.pkg_id())?
+            .read_entry_uncached(tag.entry_index() as _)
+    }
+
+    /// Opens a package (or reuses it if it's already open) and returns a handle for reading entries from it directly.
+    /// The package stays open for as long as the handle exists, even if it gets closed in the manager
+    pub fn reader_for(&self, pkg_id: u16) -> anyhow::Result<PackageReader> {
+        Ok(PackageReader {
+            pkg_id,
+            package: self.get_or_load_pkg(pkg_id)?,
+        })
+    }
 
-                let named_tags = pkg.named_tags();
+    /// Reads a tag from a specific language variant of its package, eg. the french version of an audio package.
+    /// Fails if no variant in that language was discovered. Reads from the regular package if it's already in the
+    /// requested language
+    pub fn read_tag_localized(
+        &self,
+        tag: impl Into<TagHash>,
+        language: PackageLanguage,
+    ) -> anyhow::Result<Vec<u8>> {
+        let _span = tracing::debug_span!("PackageManager::read_tag_localized").entered();
+        let tag = tag.into();
+        let pkg_id = tag.pkg_id();
+        if self.package_language(pkg_id) == Some(language) {
+            return self.read_tag(tag);
+        }
 
-                Some((entries, hashes, named_tags))
-            })
-            .collect();
+        let key = (pkg_id, language);
+        let existing = self.localized_pkgs.read().get(&key).cloned();
+        let pkg = match existing {
+            Some(pkg) => pkg,
+            None => {
+                let path = self
+                    .localized_paths
+                    .read()
+                    .get(&pkg_id)
+                    .and_then(|paths| {
+                        paths
+                            .iter()
+                            .find(|p| {
+                                p.language.as_deref().and_then(PackageLanguage::from_code)
+                                    == Some(language)
+                            })
+                            .cloned()
+                    })
+                    .with_context(|| format!("Package {pkg_id:04x} has no {language:?} variant"))?;
+
+                let pkg = self
+                    .version
+                    .open_with_options(&path.path, &self.open_options)
+                    .with_context(|| format!("Failed to open package '{}'", path.filename))?;
+                self.localized_pkgs.write().insert(key, Arc::clone(&pkg));
+                pkg
+            }
+        };
 
-        let (entries, hashes, named_tags): (_, Vec<_>, Vec<_>) = tables.into_iter().multiunzip();
+        pkg.read_entry(tag.entry_index() as _)
+    }
 
-        self.package_entry_index = entries;
-        self.hash64_table = hashes.into_iter().flatten().collect();
-        self.named_tags = named_tags.into_iter().flatten().collect();
+    /// Reads a tag along with the file extension guessed for it by [classify_file]
+    pub fn read_tag_classified(
+        &self,
+        tag: impl Into<TagHash>,
+    ) -> anyhow::Result<(Vec<u8>, String)> {
+        let tag = tag.into();
+        let entry = self
+            .get_entry(tag)
+            .with_context(|| format!("Tag {tag} does not exist"))?;
 
-        info!("Loaded {} packages", self.package_entry_index.len());
+        let data = self.read_tag(tag)?;
+        Ok((
+            data,
+            classify_file(self.version, entry.file_type, entry.file_subtype),
+        ))
     }
-}
 
-impl PackageManager {
-    pub fn get_all_by_reference(&self, reference: u32) -> Vec<(TagHash, UEntryHeader)> {
-        self.package_entry_index
-            .par_iter()
-            .map(|(p, e)| {
-                e.iter()
-                    .enumerate()
-                    .filter(|(_, e)| e.reference == reference)
-                    .map(|(i, e)| (TagHash::new(*p, i as _), e.clone()))
-                    .collect::<Vec<(TagHash, UEntryHeader)>>()
-            })
-            .flatten()
-            .collect()
+    /// Hashes the contents of a tag, streaming the data through the hasher block by block
+    #[cfg(any(feature = "xxh3", feature = "sha256"))]
+    pub fn hash_tag(&self, tag: impl Into<TagHash>, algo: HashAlgo) -> anyhow::Result<Vec<u8>> {
+        let _span = tracing::debug_span!("PackageManager::hash_tag").entered();
+        let tag = tag.into();
+        let pkg = self.get_or_load_pkg(tag.pkg_id())?;
+        hash_entry(pkg.as_ref(), tag.entry_index() as usize, algo)
     }
 
-    pub fn get_all_by_type(&self, etype: u8, esubtype: Option<u8>) -> Vec<(TagHash, UEntryHeader)> {
-        self.package_entry_index
-            .par_iter()
-            .map(|(p, e)| {
-                e.iter()
-                    .enumerate()
-                    .filter(|(_, e)| {
-                        e.file_type == etype
-                            && esubtype.map(|t| t == e.file_subtype).unwrap_or(true)
-                    })
-                    .map(|(i, e)| (TagHash::new(*p, i as _), e.clone()))
-                    .collect::<Vec<(TagHash, UEntryHeader)>>()
-            })
-            .flatten()
+    /// Groups tags that most likely point at identical content, based on their reference, type, subtype and size.
+    /// Only groups with 2 or more tags are returned, empty entries are ignored.
+    /// This never reads any entry data, see [Self::find_duplicate_entries_by_content] for an exact comparison
+    pub fn find_duplicate_entries(&self) -> Vec<Vec<TagHash>> {
+        let mut groups: FxHashMap<(u32, u8, u8, u32), Vec<TagHash>> = FxHashMap::default();
+        for (tag, e) in self.iter_tags().filter(|(_, e)| e.file_size != 0) {
+            groups
+                .entry((e.reference, e.file_type, e.file_subtype, e.file_size))
+                .or_default()
+                .push(tag);
+        }
+
+        groups
+            .into_values()
+            .filter(|g| g.len() > 1)
+            .sorted_by_key(|g| g[0])
             .collect()
     }
 
-    fn get_or_load_pkg(&self, pkg_id: u16) -> anyhow::Result<Arc<dyn Package>> {
-        let _span = tracing::debug_span!("PackageManager::get_or_Load_pkg", pkg_id).entered();
-        let v = self.pkgs.read();
-        if let Some(pkg) = v.get(&pkg_id) {
-            Ok(Arc::clone(pkg))
-        } else {
-            drop(v);
-            let package_path = self
-                .package_paths
-                .get(&pkg_id)
-                .with_context(|| format!("Couldn't get a path for package id {pkg_id:04x}"))?;
-
-            let package = self
-                .version
-                .open(&package_path.path)
-                .with_context(|| format!("Failed to open package '{}'", package_path.filename))?;
+    /// Groups tags with identical content by hashing their data like [Self::hash_tag] does.
+    /// Only entries that share their size with at least one other entry get hashed, but this still reads a large part
+    /// of the install. Only groups with 2 or more tags are returned, empty entries are ignored
+    #[cfg(any(feature = "xxh3", feature = "sha256"))]
+    pub fn find_duplicate_entries_by_content(
+        &self,
+        algo: HashAlgo,
+    ) -> anyhow::Result<Vec<Vec<TagHash>>> {
+        let _span =
+            tracing::debug_span!("PackageManager::find_duplicate_entries_by_content").entered();
+        let mut by_size: FxHashMap<u32, Vec<TagHash>> = FxHashMap::default();
+        for (tag, e) in self.iter_tags().filter(|(_, e)| e.file_size != 0) {
+            by_size.entry(e.file_size).or_default().push(tag);
+        }
 
-            self.pkgs.write().insert(pkg_id, Arc::clone(&package));
-            Ok(package)
+        // Candidates are hashed one package at a time. Packages that aren't already open are only kept open while
+        // their entries are being hashed, so this doesn't end up holding every package in the install
+        let candidates = by_size
+            .into_values()
+            .filter(|g| g.len() > 1)
+            .flatten()
+            .sorted_by_key(|t| (t.pkg_id(), t.entry_index()))
+            .chunk_by(|t| t.pkg_id());
+
+        let mut groups: FxHashMap<Vec<u8>, Vec<TagHash>> = FxHashMap::default();
+        for (pkg_id, tags) in &candidates {
+            let pkg = self.get_or_open_pkg_uncached(pkg_id)?;
+            for tag in tags {
+                let hash = hash_entry(pkg.as_ref(), tag.entry_index() as usize, algo)
+                    .with_context(|| format!("Failed to hash tag {tag}"))?;
+                groups.entry(hash).or_default().push(tag);
+            }
         }
-    }
 
-    pub fn read_tag(&self, tag: impl Into<TagHash>) -> anyhow::Result<Vec<u8>> {
-        let _span = tracing::debug_span!("PackageManager::read_tag").entered();
-        let tag = tag.into();
-        self.get_or_load_pkg(tag.pkg_id())?
-            .read_entry(tag.entry_index() as _)
+        Ok(groups
+            .into_values()
+            .filter(|g| g.len() > 1)
+            .map(|g| g.into_iter().sorted().collect_vec())
+            .sorted_by_key(|g| g[0])
+            .collect())
     }
 
     pub fn read_tag64(&self, hash: impl Into<TagHash64>) -> anyhow::Result<Vec<u8>> {
+        self.read_tag64_with_hash(hash).map(|(_, data)| data)
+    }
+
+    /// Same as [Self::read_tag64], but also returns the tag the hash resolved to
+    pub fn read_tag64_with_hash(
+        &self,
+        hash: impl Into<TagHash64>,
+    ) -> anyhow::Result<(TagHash, Vec<u8>)> {
         let hash = hash.into();
         let tag = self
+            .lookup
+            .read()
             .hash64_table
             .get(&hash.0)
             .context("Hash not found")?
             .hash32;
-        self.read_tag(tag)
+        Ok((tag, self.read_tag(tag)?))
+    }
+
+    /// Every known package along with its path, sorted by package ID.
+    /// [PackageManager::package_paths] iterates in an unspecified order, use this when the output should be reproducible
+    pub fn packages_sorted(&self) -> Vec<(u16, PackagePath)> {
+        self.package_paths
+            .read()
+            .iter()
+            .map(|(&id, p)| (id, p.clone()))
+            .sorted_by_key(|&(id, _)| id)
+            .collect()
+    }
+
+    /// Path of the package that contains the given tag, or `None` if the package isn't known to this manager
+    pub fn path_for_tag(&self, tag: impl Into<TagHash>) -> Option<PackagePath> {
+        self.package_paths.read().get(&tag.into().pkg_id()).cloned()
+    }
+
+    /// Gets the language of a package without opening it.
+    /// If the package is already open, the language from its header is preferred over the one in the filename
+    pub fn package_language(&self, pkg_id: u16) -> Option<PackageLanguage> {
+        let path = self.package_paths.read().get(&pkg_id)?.clone();
+        if let Some(pkg) = self.pkgs.read().get(&pkg_id) {
+            let language = pkg.language();
+            if !matches!(language, PackageLanguage::None) {
+                return Some(language);
+            }
+        }
+
+        Some(
+            path.language
+                .as_deref()
+                .and_then(PackageLanguage::from_code)
+                .unwrap_or(PackageLanguage::None),
+        )
+    }
+
+    /// Returns every hash64 that resolves to the given tag
+    pub fn tag64s_for_tag32(&self, tag: impl Into<TagHash>) -> Vec<TagHash64> {
+        self.lookup
+            .read()
+            .tag32_to_tag64
+            .get(&tag.into())
+            .cloned()
+            .unwrap_or_default()
     }
 
     pub fn get_entry(&self, tag: impl Into<TagHash>) -> Option<UEntryHeader> {
         let tag: TagHash = tag.into();
 
-        self.package_entry_index
+        self.lookup
+            .read()
+            .package_entry_index
             .get(&tag.pkg_id())?
             .get(tag.entry_index() as usize)
             .cloned()
     }
 
     pub fn get_named_tag(&self, name: &str, class_hash: u32) -> Option<TagHash> {
-        self.named_tags
+        self.lookup
+            .read()
+            .named_tags
             .iter()
             .find(|n| n.name == name && n.class_hash == class_hash)
             .map(|n| n.hash)
     }
 
     pub fn get_named_tags_by_class(&self, class_hash: u32) -> Vec<(String, TagHash)> {
-        self.named_tags
+        self.lookup
+            .read()
+            .named_tags
             .iter()
             .filter(|n| n.class_hash == class_hash)
             .map(|n| (n.name.clone(), n.hash))
             .collect()
     }
 
+    /// Returns every distinct named tag class hash, sorted
+    pub fn named_tag_classes(&self) -> Vec<u32> {
+        self.lookup
+            .read()
+            .named_tags
+            .iter()
+            .map(|n| n.class_hash)
+            .sorted()
+            .dedup()
+            .collect()
+    }
+
     /// Find the name of a tag by its hash, if it has one.
     pub fn get_tag_name(&self, tag: impl Into<TagHash>) -> Option<String> {
         let tag: TagHash = tag.into();
-        self.named_tags
+        self.lookup
+            .read()
+            .named_tags
             .iter()
             .find(|n| n.hash == tag)
             .map(|n| n.name.clone())
     }
 
+    /// Relative path to extract a tag to, in the form `{package name}_{package id}/{entry index}_{reference}.{ext}`.
+    /// Multiple packages can share a name, so the ID is kept in the directory name to avoid collisions.
+    /// Tags from unknown packages end up in an `unknown_{package id}` directory
+    pub fn suggested_path(&self, tag: impl Into<TagHash>, ext: &str) -> PathBuf {
+        let tag: TagHash = tag.into();
+        let dir = match self.package_paths.read().get(&tag.pkg_id()) {
+            Some(p) => format!("{}_{}", p.name, p.id),
+            None => format!("unknown_{:04x}", tag.pkg_id()),
+        };
+        let reference = self.get_entry(tag).map(|e| e.reference).unwrap_or(u32::MAX);
+
+        PathBuf::from(dir).join(format!("{}_{reference:08x}.{ext}", tag.entry_index()))
+    }
+
+    /// Extracts every entry of a package to `out_dir`, using the paths from [Self::suggested_path].
+    /// Entries are extracted in block order rather than index order, so small entries sharing a block don't cause it
+    /// to be decompressed again for every entry. Returns the amount of entries written
+    pub fn extract_package_optimized(
+        &self,
+        pkg_id: u16,
+        out_dir: impl AsRef<Path>,
+    ) -> anyhow::Result<usize> {
+        let _span = debug_span!("PackageManager::extract_package_optimized", pkg_id).entered();
+        let pkg = self.get_or_load_pkg(pkg_id)?;
+        let entries = pkg.entries();
+        let extract_order = (0..entries.len())
+            .sorted_by_key(|&i| (entries[i].starting_block, entries[i].starting_block_offset))
+            .collect_vec();
+
+        for &i in &extract_order {
+            let entry = &entries[i];
+            let ext = classify_file(self.version, entry.file_type, entry.file_subtype);
+            let path = out_dir
+                .as_ref()
+                .join(self.suggested_path(TagHash::new(pkg_id, i as u16), &ext));
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            let mut f = fs::File::create(&path)
+                .with_context(|| format!("Failed to create {}", path.display()))?;
+            pkg.copy_entry_to(i, &mut f)
+                .with_context(|| format!("Failed to extract entry {i} of package {pkg_id:04x}"))?;
+        }
+
+        Ok(extract_order.len())
+    }
+
     /// Read any BinRead type
     pub fn read_tag_binrw<'a, T: BinRead>(&self, tag: impl Into<TagHash>) -> anyhow::Result<T>
     where
@@ -426,6 +1915,7 @@ impl PackageManager {
 #[derive(serde::Serialize, serde::Deserialize)]
 pub(crate) struct PathCache {
     cache_version: usize,
+    #[serde(serialize_with = "serialize_sorted")]
     versions: HashMap<String, PathCacheEntry>,
 }
 
@@ -439,7 +1929,7 @@ impl Default for PathCache {
 }
 
 impl PathCache {
-    pub const VERSION: usize = 4;
+    pub const VERSION: usize = 5;
 
     /// Gets path cache entry by version and platform
     /// If `platform` is None, the first
@@ -449,16 +1939,21 @@ impl PathCache {
         version: GameVersion,
         platform: Option<PackagePlatform>,
         base_path: Option<&Path>,
+        extra_paths: &[PathBuf],
     ) -> anyhow::Result<Option<&PathCacheEntry>> {
         if let Some(platform) = platform {
-            return Ok(self.versions.get(&format!("{}_{}", version.id(), platform)));
+            return Ok(self
+                .versions
+                .get(&cache_key(version, platform, extra_paths)));
         }
 
         let mut matches = self
             .versions
             .iter()
             .filter(|(k, v)| {
-                v.version == version && platform.map(|p| v.platform == p).unwrap_or(true)
+                v.version == version
+                    && platform.map(|p| v.platform == p).unwrap_or(true)
+                    && v.extra_paths == extra_paths
             })
             .map(|(_, v)| v)
             .collect_vec();
@@ -478,6 +1973,21 @@ impl PathCache {
 
         Ok(matches.first().map(|v| *v))
     }
+
+    /// Finds a cache entry for an install with the given [InstallFingerprint] hash, wherever it was located
+    fn find_by_fingerprint(
+        &self,
+        version: GameVersion,
+        platform: Option<PackagePlatform>,
+        fingerprint: u64,
+    ) -> Option<&PathCacheEntry> {
+        self.versions.values().find(|v| {
+            v.version == version
+                && platform.map(|p| v.platform == p).unwrap_or(true)
+                && v.fingerprint != 0
+                && v.fingerprint == fingerprint
+        })
+    }
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -487,7 +1997,232 @@ pub(crate) struct PathCacheEntry {
     version: GameVersion,
     platform: PackagePlatform,
     base_path: PathBuf,
+    #[serde(default)]
+    extra_paths: Vec<PathBuf>,
+    /// See [InstallFingerprint]
+    #[serde(default)]
+    fingerprint: u64,
+    #[serde(serialize_with = "serialize_sorted")]
     paths: FxHashMap<u16, String>,
+    /// Non-english package variants, see [PackageManager::localized_paths]
+    #[serde(default, serialize_with = "serialize_sorted")]
+    localized_paths: FxHashMap<u16, Vec<String>>,
+}
+
+/// Identifies an install by the ID, patch number and size of its package files, independent of where it's located.
+/// This is what [PackageManager::install_fingerprint] returns, and what the package cache is matched by
+struct InstallFingerprint {
+    hash: u64,
+    /// Full path of every package file by filename. Later directories take priority
+    files: FxHashMap<String, String>,
+}
+
+impl InstallFingerprint {
+    /// Lists the package files without opening any of them. Packages without an ID in their filename are relocatable,
+    /// but aren't part of the hash
+    fn new(
+        packages_dir: &Path,
+        extra_dirs: &[PathBuf],
+        open_options: &PackageOpenOptions,
+    ) -> anyhow::Result<Self> {
+        let mut paths = vec![];
+        if let Some(source) = &open_options.source {
+            paths = source.list_packages()?;
+        } else {
+            for dir in std::iter::once(packages_dir).chain(extra_dirs.iter().map(PathBuf::as_path))
+            {
+                for entry in fs::read_dir(dir)? {
+                    let path = entry?.path().to_string_lossy().to_string();
+                    if path.to_lowercase().ends_with(".pkg") {
+                        paths.push(path);
+                    }
+                }
+            }
+        }
+
+        let mut files = FxHashMap::default();
+        let mut packages = vec![];
+        for path in paths {
+            if let Some(pp) = PackagePath::parse(&path) {
+                let max_patch = open_options.max_patch.unwrap_or(u16::MAX);
+                if let Some(pkg_id) = pp.numeric_id().filter(|_| pp.patch as u16 <= max_patch) {
+                    packages.push((pkg_id, pp.patch, open_options.file_size(&path)?));
+                }
+            }
+
+            if let Some(filename) = Path::new(&path).file_name() {
+                files.insert(filename.to_string_lossy().to_string(), path);
+            }
+        }
+
+        packages.sort();
+        let mut hasher = rustc_hash::FxHasher::default();
+        packages.hash(&mut hasher);
+
+        Ok(Self {
+            hash: hasher.finish(),
+            files,
+        })
+    }
+
+    /// Maps the paths and localized paths of a cache entry to the package files in the current directories.
+    /// Returns `None` if any of the packages can't be found
+    #[allow(clippy::type_complexity)]
+    fn relocate(
+        &self,
+        entry: &PathCacheEntry,
+    ) -> Option<(FxHashMap<u16, String>, FxHashMap<u16, Vec<String>>)> {
+        let paths = entry
+            .paths
+            .iter()
+            .map(|(id, path)| Some((*id, self.relocate_path(path)?)))
+            .collect::<Option<_>>()?;
+
+        let localized_paths = entry
+            .localized_paths
+            .iter()
+            .map(|(id, paths)| {
+                let paths = paths
+                    .iter()
+                    .map(|p| self.relocate_path(p))
+                    .collect::<Option<_>>()?;
+                Some((*id, paths))
+            })
+            .collect::<Option<_>>()?;
+
+        Some((paths, localized_paths))
+    }
+
+    fn relocate_path(&self, path: &str) -> Option<String> {
+        let filename = Path::new(path).file_name()?.to_string_lossy();
+        self.files.get(filename.as_ref()).cloned()
+    }
+}
+
+fn cache_key(version: GameVersion, platform: PackagePlatform, extra_dirs: &[PathBuf]) -> String {
+    if extra_dirs.is_empty() {
+        return format!("{}_{}", version.id(), platform);
+    }
+
+    // Directory order decides which package wins, so the paths are hashed in order
+    let mut hasher = rustc_hash::FxHasher::default();
+    extra_dirs.hash(&mut hasher);
+    format!("{}_{}_{:016x}", version.id(), platform, hasher.finish())
+}
+
+/// Newest modification time of the given package directories, in seconds since the unix epoch
+fn directories_timestamp(packages_dir: &Path, extra_dirs: &[PathBuf]) -> u64 {
+    std::iter::once(packages_dir)
+        .chain(extra_dirs.iter().map(PathBuf::as_path))
+        .filter_map(|d| {
+            fs::metadata(d)
+                .ok()?
+                .modified()
+                .ok()?
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .ok()
+        })
+        .map(|t| t.as_secs())
+        .max()
+        .unwrap_or(0)
+}
+
+/// Serializes a hashmap with its keys in sorted order, so the cache file doesn't change between runs
+fn serialize_sorted<K, V, H, S>(map: &HashMap<K, V, H>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    K: Ord + serde::Serialize,
+    V: serde::Serialize,
+    S: serde::Serializer,
+{
+    serializer.collect_map(map.iter().collect::<BTreeMap<_, _>>())
+}
+
+/// Packages from a custom source are cheap to list and have no directory to timestamp, so they're never cached.
+/// The cache doesn't know about `max_patch` either, so it would hand out the latest patches
+fn uses_package_cache(open_options: &PackageOpenOptions) -> bool {
+    open_options.source.is_none() && open_options.max_patch.is_none()
+}
+
+/// Hashes the contents of an entry, streaming the data through the hasher block by block
+#[cfg(any(feature = "xxh3", feature = "sha256"))]
+fn hash_entry(pkg: &dyn Package, index: usize, algo: HashAlgo) -> anyhow::Result<Vec<u8>> {
+    match algo {
+        #[cfg(feature = "xxh3")]
+        HashAlgo::Xxh3 => {
+            let mut hasher = xxhash_rust::xxh3::Xxh3::new();
+            pkg.read_entry_chunked(index, &mut |chunk| {
+                hasher.update(chunk);
+                Ok(())
+            })?;
+
+            Ok(hasher.digest128().to_be_bytes().to_vec())
+        }
+        #[cfg(feature = "sha256")]
+        HashAlgo::Sha256 => {
+            use sha2::Digest;
+
+            let mut hasher = sha2::Sha256::new();
+            pkg.read_entry_chunked(index, &mut |chunk| {
+                hasher.update(chunk);
+                Ok(())
+            })?;
+
+            Ok(hasher.finalize().to_vec())
+        }
+    }
+}
+
+/// Moves the paths of packages whose header ID doesn't match the ID they were registered under to the header ID.
+/// All mismatched paths are taken out first, so packages with swapped IDs don't overwrite each other
+fn rekey_by_header_id(package_paths: &mut FxHashMap<u16, PackagePath>, tables: &[PackageTables]) {
+    let moved = tables
+        .iter()
+        .filter(|t| t.pkg_id != t.path_id)
+        .filter_map(|t| Some((t.pkg_id, package_paths.remove(&t.path_id)?, t.path_id)))
+        .collect_vec();
+
+    for (pkg_id, path, path_id) in moved {
+        warn!(
+            "Package '{}' has ID {pkg_id:04x} in its header, expected {path_id:04x}. Using the header ID",
+            path.filename
+        );
+        package_paths.insert(pkg_id, path);
+    }
+}
+
+/// Parses the paths returned by [PackageManager::discover_packages] (or loaded from the package cache)
+#[allow(clippy::type_complexity)]
+fn parse_package_paths(
+    packages: FxHashMap<u16, String>,
+    localized: FxHashMap<u16, Vec<String>>,
+) -> (
+    FxHashMap<u16, PackagePath>,
+    FxHashMap<u16, Vec<PackagePath>>,
+) {
+    let package_paths = packages
+        .into_iter()
+        .map(|(id, p)| (id, PackagePath::parse_with_defaults(&p)))
+        .collect();
+    let localized_paths = localized
+        .into_iter()
+        .map(|(id, paths)| {
+            let paths = paths
+                .iter()
+                .map(|p| PackagePath::parse_with_defaults(p))
+                .collect();
+            (id, paths)
+        })
+        .collect();
+
+    (package_paths, localized_paths)
+}
+
+fn check_cancelled(cancel: Option<&AtomicBool>) -> anyhow::Result<()> {
+    if cancel.is_some_and(|c| c.load(Ordering::Relaxed)) {
+        Err(PackageError::Cancelled.into())
+    } else {
+        Ok(())
+    }
 }
 
 #[cfg(not(feature = "ignore_package_cache"))]
@@ -504,6 +2239,33 @@ fn exe_relative_path(path: &str) -> PathBuf {
     exe_directory().join(path)
 }
 
+#[cfg(not(feature = "ignore_package_cache"))]
+fn package_cache_path(cache_dir: Option<&Path>) -> PathBuf {
+    match cache_dir {
+        Some(dir) => dir.join("package_cache.json"),
+        None => exe_relative_path("package_cache.json"),
+    }
+}
+
+/// Per-user cache directory for this crate, following the platform conventions
+fn shared_cache_dir() -> Option<PathBuf> {
+    let env_dir = |var: &str| {
+        std::env::var_os(var)
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+    };
+
+    let base = if cfg!(windows) {
+        env_dir("LOCALAPPDATA")?
+    } else if cfg!(target_os = "macos") {
+        env_dir("HOME")?.join("Library/Caches")
+    } else {
+        env_dir("XDG_CACHE_HOME").or_else(|| Some(env_dir("HOME")?.join(".cache")))?
+    };
+
+    Some(base.join("destiny-pkg"))
+}
+
 #[derive(Debug, Clone)]
 pub struct PackagePath {
     /// eg. ps3, w64
@@ -525,21 +2287,32 @@ pub struct PackagePath {
 
 impl PackagePath {
     /// Example path: ps3_arch_fallen_0059_0.pkg
+    /// The platform prefix is optional, if it's missing or not recognized the platform will be "unknown"
     pub fn parse(path: &str) -> Option<Self> {
         let path_filename = Path::new(path).file_name()?.to_string_lossy();
         let parts: Vec<&str> = path_filename.split('_').collect();
-        if parts.len() < 4 {
+        if parts.len() < 3 {
             return None;
         }
 
-        let platform = parts[0].to_string();
-        let mut name = parts[1..parts.len() - 2].join("_");
+        let (platform, name_start) = if PackagePlatform::from_str(parts[0]).is_ok() {
+            (parts[0].to_string(), 1)
+        } else {
+            ("unknown".to_string(), 0)
+        };
+
+        // Everything after the platform needs at least a name, an ID and a patch number
+        if parts.len() - name_start < 3 {
+            return None;
+        }
+
+        let mut name = parts[name_start..parts.len() - 2].join("_");
         let mut id = parts[parts.len() - 2].to_string();
         let mut language = None;
         if id.len() == 2 {
             // ID is actually language code
             language = Some(id.clone());
-            name = parts[1..parts.len() - 3].join("_");
+            name = parts[name_start..parts.len() - 3].join("_");
             id = parts[parts.len() - 3].to_string();
         }
 
@@ -556,6 +2329,25 @@ impl PackagePath {
         })
     }
 
+    /// The package ID as a number, or `None` if the ID is not hexadecimal (eg. unp1)
+    pub fn numeric_id(&self) -> Option<u16> {
+        u16::from_str_radix(&self.id, 16).ok()
+    }
+
+    /// Identifies the package independent of its patch and language, eg. `w64_sr_audio_063c`
+    pub fn cache_tag(&self) -> String {
+        format!("{}_{}_{}", self.platform, self.name, self.id)
+    }
+
+    /// Whether this is the english variant of a package. Packages without a language code are treated as english
+    pub fn is_english(&self) -> bool {
+        matches!(self.language.as_deref(), None | Some("en"))
+    }
+
+    pub fn is_patch_zero(&self) -> bool {
+        self.patch == 0
+    }
+
     pub fn parse_with_defaults(path: &str) -> Self {
         let path_filename = Path::new(path)
             .file_name()
@@ -572,8 +2364,533 @@ impl PackagePath {
     }
 }
 
+/// Paths are equal when they point to the same file
+impl PartialEq for PackagePath {
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path
+    }
+}
+
+impl Eq for PackagePath {}
+
+/// Sorts by package ID, then patch. Non-numeric IDs (eg. unp1) come first, and the full path breaks any remaining ties
+impl Ord for PackagePath {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.numeric_id(), &self.id, self.patch, &self.path).cmp(&(
+            other.numeric_id(),
+            &other.id,
+            other.patch,
+            &other.path,
+        ))
+    }
+}
+
+impl PartialOrd for PackagePath {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 impl Display for PackagePath {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.filename)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, TEST_VERSION};
+
+    /// Builder for a manager over `dir` that keeps its package cache inside the directory
+    fn builder(dir: &Path) -> PackageManagerBuilder {
+        PackageManager::builder(dir, TEST_VERSION).cache_dir(dir.join("cache"))
+    }
+
+    #[cfg(feature = "zip")]
+    #[test]
+    fn indexes_through_zip_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let package = test_util::package_bytes(0x123, &[(0x80800000, b"zipped")]);
+
+        let zip_path = dir.path().join("packages.zip");
+        let mut zip = zip::ZipWriter::new(fs::File::create(&zip_path).unwrap());
+        zip.start_file(
+            "w64_test_0123_0.pkg",
+            zip::write::SimpleFileOptions::default(),
+        )
+        .unwrap();
+        zip.write_all(&package).unwrap();
+        zip.finish().unwrap();
+
+        let source = crate::source::ZipSource::open(&zip_path).unwrap();
+        let manager = builder(dir.path())
+            .source(Arc::new(source))
+            .build()
+            .unwrap();
+
+        let tag = TagHash::new(0x123, 0);
+        assert!(manager.failed_packages().is_empty());
+        assert!(manager.get_entry(tag).is_some());
+        assert_eq!(manager.read_tag(tag).unwrap(), b"zipped");
+        assert_eq!(manager.total_install_size(), package.len() as u64);
+    }
+
+    #[test]
+    fn reindex_picks_up_added_packages() {
+        let dir = tempfile::tempdir().unwrap();
+        test_util::write_package(
+            dir.path(),
+            "w64_test_0123_0.pkg",
+            0x123,
+            &[(0x80800000, b"a")],
+        );
+        let manager = builder(dir.path()).build().unwrap();
+
+        let added = TagHash::new(0x124, 0);
+        assert!(manager.get_entry(added).is_none());
+
+        test_util::write_package(
+            dir.path(),
+            "w64_test_0124_0.pkg",
+            0x124,
+            &[(0x80800000, b"b")],
+        );
+        manager.reindex().unwrap();
+
+        assert_eq!(manager.total_package_count(), 2);
+        assert!(manager.get_entry(added).is_some());
+        assert_eq!(manager.read_tag(added).unwrap(), b"b");
+        assert_eq!(manager.read_tag(TagHash::new(0x123, 0)).unwrap(), b"a");
+    }
+
+    #[test]
+    fn indexes_with_patch_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let patch_dir = tempfile::tempdir().unwrap();
+
+        // Patch 1 only has a header, all of its blocks are still stored in patch 0, which lives in the patch directory
+        let patch0 = test_util::package_bytes(0x123, &[(0x80800000, b"from patch 0")]);
+        let mut patch1 = patch0.clone();
+        patch1[0x30..0x32].copy_from_slice(&1u16.to_le_bytes());
+        fs::write(patch_dir.path().join("w64_test_0123_0.pkg"), &patch0).unwrap();
+        fs::write(dir.path().join("w64_test_0123_1.pkg"), &patch1).unwrap();
+
+        let tag = TagHash::new(0x123, 0);
+        let manager = builder(dir.path())
+            .patch_dir(patch_dir.path())
+            .build()
+            .unwrap();
+        assert!(manager.failed_packages().is_empty());
+        assert!(manager.get_entry(tag).is_some());
+        assert_eq!(manager.read_tag(tag).unwrap(), b"from patch 0");
+        assert_eq!(
+            manager.total_install_size(),
+            (patch0.len() + patch1.len()) as u64
+        );
+
+        let manager = builder(dir.path()).build().unwrap();
+        assert!(manager.read_tag(tag).is_err());
+    }
+
+    #[test]
+    fn indexes_with_patch_naming() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // Patch 0 is stored under a name that isn't discovered as a package, only the patch naming finds it
+        let patch0 = test_util::package_bytes(0x123, &[(0x80800000, b"from patch 0")]);
+        let mut patch1 = patch0.clone();
+        patch1[0x30..0x32].copy_from_slice(&1u16.to_le_bytes());
+        fs::write(dir.path().join("w64_test_0123_0.pkg.old"), &patch0).unwrap();
+        fs::write(dir.path().join("w64_test_0123_1.pkg"), &patch1).unwrap();
+
+        let tag = TagHash::new(0x123, 0);
+        let manager = builder(dir.path())
+            .patch_naming(|base, patch| format!("{base}_{patch}.pkg.old"))
+            .build()
+            .unwrap();
+        assert!(manager.failed_packages().is_empty());
+        assert_eq!(manager.read_tag(tag).unwrap(), b"from patch 0");
+        assert_eq!(
+            manager.total_install_size(),
+            (patch0.len() + patch1.len()) as u64
+        );
+    }
+
+    #[test]
+    fn indexes_up_to_max_patch() {
+        let dir = tempfile::tempdir().unwrap();
+        test_util::write_package(
+            dir.path(),
+            "w64_test_0123_0.pkg",
+            0x123,
+            &[(0x80800000, b"old")],
+        );
+
+        let mut writer = test_util::writer(0x123);
+        writer.patch_id = 1;
+        writer.add_entry(0x80800000, 8, 0, b"new".to_vec()).unwrap();
+        writer
+            .add_entry(0x80800000, 8, 0, b"added".to_vec())
+            .unwrap();
+        fs::write(
+            dir.path().join("w64_test_0123_1.pkg"),
+            test_util::write(&writer),
+        )
+        .unwrap();
+
+        let tag = TagHash::new(0x123, 0);
+        let manager = builder(dir.path()).max_patch(0).build().unwrap();
+        assert_eq!(manager.total_entry_count(), 1);
+        assert_eq!(manager.read_tag(tag).unwrap(), b"old");
+
+        let manager = builder(dir.path()).build().unwrap();
+        assert_eq!(manager.total_entry_count(), 2);
+        assert_eq!(manager.read_tag(tag).unwrap(), b"new");
+    }
+
+    #[test]
+    fn register_key_closes_localized_packages() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = test_util::writer(0x123);
+        writer.group_id = 0x1234;
+        writer.add_entry(0x80800000, 8, 0, b"en".to_vec()).unwrap();
+        fs::write(
+            dir.path().join("w64_test_0123_0.pkg"),
+            test_util::write(&writer),
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("w64_test_0123_fr_0.pkg"),
+            test_util::write(&writer),
+        )
+        .unwrap();
+
+        let manager = builder(dir.path()).build().unwrap();
+        let tag = TagHash::new(0x123, 0);
+        manager.read_tag(tag).unwrap();
+        manager
+            .read_tag_localized(tag, PackageLanguage::French)
+            .unwrap();
+        assert_eq!(manager.pkgs.read().len(), 1);
+        assert_eq!(manager.localized_pkgs.read().len(), 1);
+
+        manager.register_key(0x1234, [0; 16], [0; 12]);
+        assert!(manager.pkgs.read().is_empty());
+        assert!(manager.localized_pkgs.read().is_empty());
+    }
+
+    #[test]
+    fn mismatched_package_ids_use_header_id() {
+        let dir = tempfile::tempdir().unwrap();
+        test_util::write_package(
+            dir.path(),
+            "w64_test_0124_0.pkg",
+            0x123,
+            &[(0x80800000, b"a")],
+        );
+
+        let manager = builder(dir.path()).build().unwrap();
+        let tag = TagHash::new(0x123, 0);
+        assert!(manager.package_paths.read().get(&0x124).is_none());
+        assert_eq!(
+            manager.path_for_tag(tag).unwrap().filename,
+            "w64_test_0124_0.pkg"
+        );
+        assert_eq!(manager.read_tag(tag).unwrap(), b"a");
+
+        // Same rule when a single package gets indexed
+        test_util::write_package(
+            dir.path(),
+            "w64_test_0125_0.pkg",
+            0x126,
+            &[(0x80800000, b"b")],
+        );
+        let path = PackagePath::parse_with_defaults(
+            &dir.path().join("w64_test_0125_0.pkg").to_string_lossy(),
+        );
+        manager.package_paths.write().insert(0x125, path);
+        manager.index_package(0x125).unwrap();
+
+        let tag = TagHash::new(0x126, 0);
+        assert!(manager.package_paths.read().get(&0x125).is_none());
+        assert!(manager.get_entry(TagHash::new(0x125, 0)).is_none());
+        assert_eq!(manager.read_tag(tag).unwrap(), b"b");
+    }
+
+    #[test]
+    fn multiple_hash64s_resolve_to_the_same_tag() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = test_util::writer(0x123);
+        let tag = writer.add_entry(0x80800000, 8, 0, b"a".to_vec()).unwrap();
+        writer.add_hash64(0x2000_0000_0000_0001, tag, TagHash(0x80800000));
+        writer.add_hash64(0x1000_0000_0000_0002, tag, TagHash(0x80800000));
+        fs::write(
+            dir.path().join("w64_test_0123_0.pkg"),
+            test_util::write(&writer),
+        )
+        .unwrap();
+
+        let manager = builder(dir.path()).build().unwrap();
+        for hash in [0x2000_0000_0000_0001, 0x1000_0000_0000_0002] {
+            let (resolved, data) = manager.read_tag64_with_hash(TagHash64(hash)).unwrap();
+            assert_eq!(resolved, tag);
+            assert_eq!(data, b"a");
+        }
+
+        assert_eq!(
+            manager.tag64s_for_tag32(tag),
+            [
+                TagHash64(0x1000_0000_0000_0002),
+                TagHash64(0x2000_0000_0000_0001)
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_package_path() {
+        // (path, platform, name, language, id, patch)
+        type Case = (
+            &'static str,
+            &'static str,
+            &'static str,
+            Option<&'static str>,
+            &'static str,
+            u8,
+        );
+        let cases: &[Case] = &[
+            (
+                "ps3_arch_fallen_0059_0.pkg",
+                "ps3",
+                "arch_fallen",
+                None,
+                "0059",
+                0,
+            ),
+            (
+                "w64_sr_gear_0426_10.pkg",
+                "w64",
+                "sr_gear",
+                None,
+                "0426",
+                10,
+            ),
+            (
+                "packages/w64_test_unp1_2.pkg",
+                "w64",
+                "test",
+                None,
+                "unp1",
+                2,
+            ),
+            (
+                "arch_fallen_0059_1.pkg",
+                "unknown",
+                "arch_fallen",
+                None,
+                "0059",
+                1,
+            ),
+            (
+                "w64_audio_0123_en_3.pkg",
+                "w64",
+                "audio",
+                Some("en"),
+                "0123",
+                3,
+            ),
+            (
+                "w64_audio_0123_fr_0.pkg",
+                "w64",
+                "audio",
+                Some("fr"),
+                "0123",
+                0,
+            ),
+            (
+                "audio_0123_de_0.pkg",
+                "unknown",
+                "audio",
+                Some("de"),
+                "0123",
+                0,
+            ),
+        ];
+
+        for &(path, platform, name, language, id, patch) in cases {
+            let p = PackagePath::parse(path).unwrap_or_else(|| panic!("Failed to parse {path}"));
+            assert_eq!(p.platform, platform, "{path}");
+            assert_eq!(p.name, name, "{path}");
+            assert_eq!(p.language.as_deref(), language, "{path}");
+            assert_eq!(p.id, id, "{path}");
+            assert_eq!(p.patch, patch, "{path}");
+            assert_eq!(p.path, path, "{path}");
+            assert_eq!(
+                p.filename,
+                Path::new(path).file_name().unwrap().to_string_lossy()
+            );
+        }
+
+        for path in [
+            "",
+            "test.pkg",
+            "w64_0123.pkg",
+            "w64_test_0123.pkg",
+            "w64_test_0123_x.pkg",
+            "w64_test_0123_-1.pkg",
+        ] {
+            assert!(
+                PackagePath::parse(path).is_none(),
+                "{path} should not parse"
+            );
+        }
+    }
+
+    #[cfg(not(feature = "ignore_package_cache"))]
+    #[test]
+    fn cache_is_keyed_by_discovered_platform() {
+        let dir = tempfile::tempdir().unwrap();
+        test_util::write_package(
+            dir.path(),
+            "w64_test_0123_0.pkg",
+            0x123,
+            &[(0x80800000, b"a")],
+        );
+
+        let manager = builder(dir.path())
+            .platform(PackagePlatform::PS4)
+            .build()
+            .unwrap();
+        assert_eq!(manager.cache_status(), CacheStatus::Rebuilt);
+        assert_eq!(manager.platform, PackagePlatform::Windows);
+        assert!(manager.cache_key().ends_with("_w64"));
+
+        let cache =
+            PackageManager::read_package_cache(Some(&dir.path().join("cache")), true).unwrap();
+        assert!(cache
+            .get_paths(TEST_VERSION, Some(PackagePlatform::PS4), None, &[])
+            .unwrap()
+            .is_none());
+        let entry = cache
+            .get_paths(TEST_VERSION, Some(PackagePlatform::Windows), None, &[])
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.platform, PackagePlatform::Windows);
+
+        let manager = builder(dir.path())
+            .platform(PackagePlatform::Windows)
+            .build()
+            .unwrap();
+        assert_eq!(manager.cache_status(), CacheStatus::LoadedFromCache);
+        assert_eq!(manager.read_tag(TagHash::new(0x123, 0)).unwrap(), b"a");
+    }
+
+    #[cfg(not(feature = "ignore_package_cache"))]
+    #[test]
+    fn cache_is_matched_by_install_fingerprint() {
+        let dir = tempfile::tempdir().unwrap();
+        test_util::write_package(
+            dir.path(),
+            "w64_test_0123_0.pkg",
+            0x123,
+            &[(0x80800000, b"a")],
+        );
+
+        let manager = builder(dir.path()).defer_indexing().build().unwrap();
+        let fingerprint = manager.install_fingerprint();
+        let cache =
+            PackageManager::read_package_cache(Some(&dir.path().join("cache")), true).unwrap();
+        let entry = cache
+            .get_paths(TEST_VERSION, Some(PackagePlatform::Windows), None, &[])
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.fingerprint, fingerprint);
+
+        test_util::write_package(
+            dir.path(),
+            "w64_test_0123_1.pkg",
+            0x123,
+            &[(0x80800000, b"b")],
+        );
+        assert_ne!(manager.install_fingerprint(), fingerprint);
+    }
+
+    #[test]
+    fn strict_platform_check_rejects_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        test_util::write_package(
+            dir.path(),
+            "w64_test_0123_0.pkg",
+            0x123,
+            &[(0x80800000, b"a")],
+        );
+
+        let result = builder(dir.path())
+            .platform(PackagePlatform::PS4)
+            .strict_platform_check()
+            .build();
+        let message = result.err().unwrap().to_string();
+        assert!(
+            message.contains("ps4") && message.contains("w64"),
+            "{message}"
+        );
+    }
+
+    #[cfg(not(feature = "ignore_package_cache"))]
+    #[test]
+    fn concurrent_cache_writes_keep_every_entry() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let dirs = (0..8u16)
+            .map(|i| {
+                let dir = tempfile::tempdir().unwrap();
+                let filename = format!("w64_test_{:04x}_0.pkg", 0x100 + i);
+                test_util::write_package(dir.path(), &filename, 0x100 + i, &[(0x80800000, b"a")]);
+                dir
+            })
+            .collect_vec();
+
+        std::thread::scope(|scope| {
+            for dir in &dirs {
+                scope.spawn(|| {
+                    PackageManager::builder(dir.path(), TEST_VERSION)
+                        .shared_cache()
+                        .cache_dir(cache_dir.path())
+                        .defer_indexing()
+                        .build()
+                        .unwrap()
+                });
+            }
+        });
+
+        let cache = PackageManager::read_package_cache(Some(cache_dir.path()), true).unwrap();
+        assert_eq!(cache.versions.len(), dirs.len());
+    }
+
+    #[cfg(feature = "xxh3")]
+    #[test]
+    fn duplicates_by_content_dont_keep_packages_open() {
+        let dir = tempfile::tempdir().unwrap();
+        test_util::write_package(
+            dir.path(),
+            "w64_test_0123_0.pkg",
+            0x123,
+            &[(0x80800000, b"same"), (0x80800000, b"diff")],
+        );
+        test_util::write_package(
+            dir.path(),
+            "w64_test_0124_0.pkg",
+            0x124,
+            &[(0x80800001, b"same")],
+        );
+
+        let manager = builder(dir.path()).build().unwrap();
+        let groups = manager
+            .find_duplicate_entries_by_content(HashAlgo::Xxh3)
+            .unwrap();
+        assert_eq!(
+            groups,
+            [vec![TagHash::new(0x123, 0), TagHash::new(0x124, 0)]]
+        );
+        assert!(manager.pkgs.read().is_empty());
+    }
+}
@@ -1,26 +1,41 @@
-use std::{ffi::c_void, path::Path, ptr::null_mut};
+#[cfg(all(feature = "reader", feature = "oodle"))]
+use std::cell::RefCell;
+use std::path::Path;
+#[cfg(feature = "oodle")]
+use std::{ffi::c_void, ptr::null_mut};
 
+#[cfg(feature = "oodle")]
+use anyhow::Context;
+#[cfg(feature = "oodle")]
 use lazy_static::lazy_static;
-#[cfg(unix)]
+#[cfg(all(feature = "oodle", unix))]
 use libloading::os::unix as ll_impl;
-#[cfg(windows)]
+#[cfg(all(feature = "oodle", windows))]
 use libloading::os::windows as ll_impl;
+#[cfg(feature = "oodle")]
 use libloading::Library;
+#[cfg(feature = "oodle")]
 use parking_lot::RwLock;
+#[cfg(feature = "oodle")]
 use tracing::info;
 
+use crate::error::PackageError;
+
+#[cfg(feature = "oodle")]
 #[repr(u32)]
 enum OodleLzFuzzSafe {
     No = 0,
     Yes = 1,
 }
 
+#[cfg(feature = "oodle")]
 #[repr(u32)]
 enum OodleLzCheckCRC {
     No = 0,
     Yes = 1,
 }
 
+#[cfg(feature = "oodle")]
 #[repr(u32)]
 enum OodleLzVerbosity {
     None = 0,
@@ -29,6 +44,7 @@ enum OodleLzVerbosity {
     Lots = 3,
 }
 
+#[cfg(feature = "oodle")]
 #[repr(u32)]
 enum OodleLzThreadPhase {
     ThreadPhase1 = 1,
@@ -36,6 +52,7 @@ enum OodleLzThreadPhase {
     ThreadPhaseAll = 3,
 }
 
+#[cfg(feature = "oodle")]
 type OodleLzDecompress = unsafe extern "C" fn(
     compBuf: *const u8,
     compBufSize: i64,
@@ -49,10 +66,42 @@ type OodleLzDecompress = unsafe extern "C" fn(
     fpCallback: *mut c_void,
     callbackUserData: *mut c_void,
     decoderMemory: *mut c_void,
-    decoderMemorySize: *const c_void,
+    decoderMemorySize: isize,
     threadPhase: OodleLzThreadPhase,
 ) -> i64;
 
+#[cfg(feature = "oodle")]
+type OodleLzCompress = unsafe extern "C" fn(
+    compressor: OodleLzCompressor,
+    raw_buf: *const u8,
+    raw_len: i64,
+    comp_buf: *mut u8,
+    level: OodleLzCompressionLevel,
+    options: *const c_void,
+    dictionary_base: *const c_void,
+    lrm: *const c_void,
+    scratch_mem: *mut c_void,
+    scratch_size: i64,
+) -> i64;
+
+#[cfg(feature = "oodle")]
+type OodleLzDecoderMemorySizeNeeded =
+    unsafe extern "C" fn(compressor: OodleLzCompressor, raw_len: isize) -> i32;
+
+#[repr(i32)]
+#[derive(Clone, Copy)]
+pub enum OodleLzCompressor {
+    /// Accepts any compressor, only valid for [Oodle::decoder_memory_size]
+    Invalid = -1,
+    Kraken = 8,
+}
+
+#[repr(i32)]
+#[derive(Clone, Copy)]
+pub enum OodleLzCompressionLevel {
+    Normal = 4,
+}
+
 #[derive(Clone, Copy)]
 pub enum OodleVersion {
     V3 = 3,
@@ -68,23 +117,26 @@ impl OodleVersion {
     }
 }
 
+#[cfg(feature = "oodle")]
 pub struct Oodle {
     _lib: Library,
     fn_decompress: ll_impl::Symbol<OodleLzDecompress>,
+    /// Not every build of Oodle exports the compressor (eg. linoodle)
+    fn_compress: Option<ll_impl::Symbol<OodleLzCompress>>,
+    fn_decoder_memory_size: Option<ll_impl::Symbol<OodleLzDecoderMemorySizeNeeded>>,
 }
 
+#[cfg(feature = "oodle")]
 unsafe impl Send for Oodle {}
+#[cfg(feature = "oodle")]
 unsafe impl Sync for Oodle {}
 
+#[cfg(feature = "oodle")]
 impl Oodle {
     pub fn new(version: OodleVersion) -> anyhow::Result<Oodle> {
-        #[cfg(target_os = "windows")]
-        let lib_path = format!("oo2core_{}_win64.dll", version.num());
-        #[cfg(target_os = "linux")]
-        let lib_path = format!("liblinoodle{}.so", version.num());
-        #[cfg(target_os = "macos")]
-        compile_error!("macOS is not supported for Oodle decompression!");
-
+        let lib_path = library_name(version).context(
+            "Oodle is not supported on this platform, use Oodle::from_path to load a library manually",
+        )?;
         let oodle = Self::from_path(lib_path)?;
         info!("Successfully loaded Oodle {}", version.num());
 
@@ -98,6 +150,16 @@ impl Oodle {
             lib.get::<OodleLzDecompress>(b"OodleLZ_Decompress")?
                 .into_raw()
         };
+        let fn_compress = unsafe {
+            lib.get::<OodleLzCompress>(b"OodleLZ_Compress")
+                .ok()
+                .map(|f| f.into_raw())
+        };
+        let fn_decoder_memory_size = unsafe {
+            lib.get::<OodleLzDecoderMemorySizeNeeded>(b"OodleLZDecoder_MemorySizeNeeded")
+                .ok()
+                .map(|f| f.into_raw())
+        };
 
         info!(
             "Successfully loaded Oodle from {}",
@@ -107,10 +169,30 @@ impl Oodle {
         Ok(Oodle {
             _lib: lib,
             fn_decompress,
+            fn_compress,
+            fn_decoder_memory_size,
         })
     }
 
+    /// Returns the decompressed size, or a value <= 0 if decompression failed
     pub fn decompress(&self, buffer: &[u8], output_buffer: &mut [u8]) -> i64 {
+        self.decompress_with_memory(buffer, output_buffer, None)
+    }
+
+    /// Same as [Oodle::decompress], but lets Oodle use `decoder_memory` instead of allocating its own decoder state on
+    /// every call. The buffer should be at least [Oodle::decoder_memory_size] bytes, Oodle falls back to allocating if
+    /// it's too small
+    pub fn decompress_with_memory(
+        &self,
+        buffer: &[u8],
+        output_buffer: &mut [u8],
+        decoder_memory: Option<&mut [u8]>,
+    ) -> i64 {
+        let (decoder_memory, decoder_memory_size) = match decoder_memory {
+            Some(m) => (m.as_mut_ptr() as *mut c_void, m.len() as isize),
+            None => (null_mut(), 0),
+        };
+
         unsafe {
             (self.fn_decompress)(
                 buffer.as_ptr() as *mut u8,
@@ -124,33 +206,236 @@ impl Oodle {
                 null_mut(),
                 null_mut(),
                 null_mut(),
+                decoder_memory,
+                decoder_memory_size,
+                OodleLzThreadPhase::ThreadPhaseAll,
+            )
+        }
+    }
+
+    /// Size of the decoder memory needed to decompress `raw_len` bytes without allocating.
+    /// Returns `None` if the library doesn't export `OodleLZDecoder_MemorySizeNeeded`
+    pub fn decoder_memory_size(
+        &self,
+        compressor: OodleLzCompressor,
+        raw_len: usize,
+    ) -> Option<usize> {
+        let fn_size = self.fn_decoder_memory_size.as_ref()?;
+        let size = unsafe { (fn_size)(compressor, raw_len as isize) };
+        (size > 0).then_some(size as usize)
+    }
+}
+
+#[cfg(feature = "oodle")]
+impl Oodle {
+    /// Returns the compressed size, or `None` if compression is not available or failed.
+    /// `output_buffer` should be at least [compress_bound] bytes
+    pub fn compress(
+        &self,
+        compressor: OodleLzCompressor,
+        level: OodleLzCompressionLevel,
+        buffer: &[u8],
+        output_buffer: &mut [u8],
+    ) -> Option<usize> {
+        let fn_compress = self.fn_compress.as_ref()?;
+        let size = unsafe {
+            (fn_compress)(
+                compressor,
+                buffer.as_ptr(),
+                buffer.len() as i64,
+                output_buffer.as_mut_ptr(),
+                level,
+                null_mut(),
+                null_mut(),
                 null_mut(),
                 null_mut(),
-                OodleLzThreadPhase::ThreadPhaseAll,
+                0,
             )
+        };
+
+        (size > 0).then_some(size as usize)
+    }
+}
+
+/// Decompresses a single package block into a buffer of `expected_len` bytes, returning only the decompressed bytes.
+/// Fails with [PackageError::OodleDecodeFailed] if Oodle returns an error code, or claims to have written more than
+/// `expected_len` bytes
+#[cfg(feature = "reader")]
+pub(crate) fn decompress_block(
+    version: OodleVersion,
+    block_index: usize,
+    data: &[u8],
+    expected_len: usize,
+) -> anyhow::Result<Vec<u8>> {
+    let mut output = vec![0u8; expected_len];
+    let code = decompress_reusing_memory(version, data, &mut output)?;
+
+    if code <= 0 || code as usize > output.len() {
+        return Err(PackageError::OodleDecodeFailed {
+            code,
+            block: block_index,
+            compressed_len: data.len(),
+            expected_len,
         }
+        .into());
     }
+
+    output.truncate(code as usize);
+    Ok(output)
 }
 
+#[cfg(all(feature = "reader", feature = "oodle"))]
+thread_local! {
+    /// Decoder state for [decompress_block], reused so Oodle doesn't allocate it for every block
+    static DECODER_MEMORY: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+#[cfg(all(feature = "reader", feature = "oodle"))]
+fn decompress_reusing_memory(
+    version: OodleVersion,
+    buffer: &[u8],
+    output_buffer: &mut [u8],
+) -> anyhow::Result<i64> {
+    let oodle = oodle_slot(version).read();
+    let oodle = oodle.as_ref().ok_or(PackageError::OodleMissing)?;
+
+    let Some(memory_size) =
+        oodle.decoder_memory_size(OodleLzCompressor::Invalid, output_buffer.len())
+    else {
+        return Ok(oodle.decompress(buffer, output_buffer));
+    };
+
+    Ok(DECODER_MEMORY.with_borrow_mut(|memory| {
+        if memory.len() < memory_size {
+            memory.resize(memory_size, 0);
+        }
+
+        oodle.decompress_with_memory(buffer, output_buffer, Some(memory))
+    }))
+}
+
+#[cfg(all(feature = "reader", not(feature = "oodle")))]
+fn decompress_reusing_memory(
+    _version: OodleVersion,
+    _buffer: &[u8],
+    _output_buffer: &mut [u8],
+) -> anyhow::Result<i64> {
+    Err(PackageError::OodleMissing.into())
+}
+
+/// Worst-case size of a compressed buffer for `size` bytes of input
+pub fn compress_bound(size: usize) -> usize {
+    size + 274 * size.div_ceil(0x40000)
+}
+
+#[cfg(feature = "oodle")]
 lazy_static! {
     pub static ref OODLE_3: RwLock<Option<Oodle>> = RwLock::new(Oodle::new(OodleVersion::V3).ok());
     pub static ref OODLE_9: RwLock<Option<Oodle>> = RwLock::new(Oodle::new(OodleVersion::V9).ok());
 }
 
-/// Fails if the library isn't loaded
+/// Platform-specific filename of the Oodle library.
+/// Returns `None` on platforms without a known library (eg. macOS), use [Oodle::from_path] to load one manually
+pub fn library_name(version: OodleVersion) -> Option<String> {
+    if cfg!(target_os = "windows") {
+        Some(format!("oo2core_{}_win64.dll", version.num()))
+    } else if cfg!(target_os = "linux") {
+        Some(format!("liblinoodle{}.so", version.num()))
+    } else {
+        None
+    }
+}
+
+#[cfg(feature = "oodle")]
+fn oodle_slot(version: OodleVersion) -> &'static RwLock<Option<Oodle>> {
+    match version {
+        OodleVersion::V3 => &OODLE_3,
+        OodleVersion::V9 => &OODLE_9,
+    }
+}
+
+/// Loads the given Oodle version ahead of time, so a missing library can be reported before any packages are read.
+/// Uses the default library search when `path` is `None`, otherwise (re)loads the library from `path`
+#[cfg(feature = "oodle")]
+pub fn preload(version: OodleVersion, path: Option<&Path>) -> anyhow::Result<()> {
+    let mut oodle = oodle_slot(version).write();
+    if path.is_none() && oodle.is_some() {
+        return Ok(());
+    }
+
+    let loaded = match path {
+        Some(path) => Oodle::from_path(path),
+        None => Oodle::new(version),
+    }
+    .with_context(|| {
+        format!(
+            "Oodle {} library not found, compressed packages won't open",
+            version.num()
+        )
+    })?;
+
+    *oodle = Some(loaded);
+    Ok(())
+}
+
+/// Whether the given Oodle version has been loaded successfully
+#[cfg(feature = "oodle")]
+pub fn is_loaded(version: OodleVersion) -> bool {
+    oodle_slot(version).read().is_some()
+}
+
+/// Fails with [PackageError::OodleMissing] if the library isn't loaded
+#[cfg(feature = "oodle")]
 pub fn decompress_3(buffer: &[u8], output_buffer: &mut [u8]) -> anyhow::Result<i64> {
     OODLE_3
         .read()
         .as_ref()
         .map(|o| o.decompress(buffer, output_buffer))
-        .ok_or_else(|| panic!("Oodle 3 isn't loaded!"))
+        .ok_or_else(|| PackageError::OodleMissing.into())
 }
 
-/// Fails if the library isn't loaded
+/// Fails with [PackageError::OodleMissing] if the library isn't loaded
+#[cfg(feature = "oodle")]
 pub fn decompress_9(buffer: &[u8], output_buffer: &mut [u8]) -> anyhow::Result<i64> {
     OODLE_9
         .read()
         .as_ref()
         .map(|o| o.decompress(buffer, output_buffer))
-        .ok_or_else(|| panic!("Oodle 9 isn't loaded!"))
+        .ok_or_else(|| PackageError::OodleMissing.into())
+}
+
+/// Compresses using Kraken. Returns `None` if the library isn't loaded or doesn't export the compressor
+#[cfg(feature = "oodle")]
+pub fn compress_9(buffer: &[u8], output_buffer: &mut [u8]) -> Option<usize> {
+    OODLE_9.read().as_ref()?.compress(
+        OodleLzCompressor::Kraken,
+        OodleLzCompressionLevel::Normal,
+        buffer,
+        output_buffer,
+    )
+}
+
+#[cfg(not(feature = "oodle"))]
+pub fn preload(_version: OodleVersion, _path: Option<&Path>) -> anyhow::Result<()> {
+    Err(PackageError::OodleMissing.into())
+}
+
+#[cfg(not(feature = "oodle"))]
+pub fn is_loaded(_version: OodleVersion) -> bool {
+    false
+}
+
+#[cfg(not(feature = "oodle"))]
+pub fn decompress_3(_buffer: &[u8], _output_buffer: &mut [u8]) -> anyhow::Result<i64> {
+    Err(PackageError::OodleMissing.into())
+}
+
+#[cfg(not(feature = "oodle"))]
+pub fn decompress_9(_buffer: &[u8], _output_buffer: &mut [u8]) -> anyhow::Result<i64> {
+    Err(PackageError::OodleMissing.into())
+}
+
+#[cfg(not(feature = "oodle"))]
+pub fn compress_9(_buffer: &[u8], _output_buffer: &mut [u8]) -> Option<usize> {
+    None
 }
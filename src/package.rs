@@ -1,22 +1,351 @@
+#[cfg(feature = "reader")]
+use std::{
+    collections::hash_map::Entry,
+    fs::File,
+    io::{BufReader, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 use std::{
     fmt::{Display, Formatter},
     io::{Read, Seek},
     str::FromStr,
-    sync::Arc,
 };
 
-use anyhow::{anyhow, ensure};
+use anyhow::anyhow;
+#[cfg(feature = "reader")]
+use anyhow::{ensure, Context};
 use binrw::{BinRead, Endian};
 use clap::ValueEnum;
+#[cfg(feature = "reader")]
+use itertools::Itertools;
+#[cfg(feature = "reader")]
+use parking_lot::RwLock;
+#[cfg(feature = "reader")]
+use rustc_hash::FxHashMap;
 
+#[cfg(feature = "reader")]
 use crate::{
-    d1_internal_alpha::PackageD1InternalAlpha, d1_legacy::PackageD1Legacy,
-    d1_roi::PackageD1RiseOfIron, d2_beta::PackageD2Beta, d2_beyondlight::PackageD2BeyondLight,
-    d2_shared::PackageNamedTagEntry, PackageD2PreBL, TagHash,
+    crypto::PackageKeys,
+    d1_internal_alpha::PackageD1InternalAlpha,
+    d1_legacy::PackageD1Legacy,
+    d1_roi::PackageD1RiseOfIron,
+    d2_beta::PackageD2Beta,
+    d2_beyondlight::PackageD2BeyondLight,
+    d2_shared::PackageNamedTagEntry,
+    error::PackageError,
+    source::{PackageSource, SourceReader},
+    tag::{PatchId, PkgId},
+    PackageD2PreBL,
 };
+use crate::{d2_shared::BLOCK_SIZE, oodle::OodleVersion, TagHash};
 
 pub const BLOCK_CACHE_SIZE: usize = 128;
 
+/// A cached block along with the counter value it was inserted at
+#[cfg(feature = "reader")]
+type CachedBlock = (usize, Arc<Vec<u8>>);
+
+/// Keeps the most recently read blocks of a package in memory, evicting the oldest ones past [BLOCK_CACHE_SIZE]
+#[cfg(feature = "reader")]
+#[derive(Default)]
+pub(crate) struct BlockCache {
+    /// Used for purging old blocks
+    counter: AtomicUsize,
+    blocks: RwLock<FxHashMap<usize, CachedBlock>>,
+}
+
+#[cfg(feature = "reader")]
+impl BlockCache {
+    /// Returns the cached block, or reads it with `read_block` and caches it.
+    /// The cache lock isn't held while reading, so cache hits on other threads don't wait for disk reads
+    pub fn get_or_read(
+        &self,
+        index: usize,
+        read_block: impl FnOnce(usize) -> anyhow::Result<Vec<u8>>,
+    ) -> anyhow::Result<Arc<Vec<u8>>> {
+        if let Some((_, b)) = self.blocks.read().get(&index) {
+            return Ok(b.clone());
+        }
+
+        let block = read_block(index)?;
+        let (_, b) = match self.blocks.write().entry(index) {
+            // Another thread read the same block in the meantime
+            Entry::Occupied(o) => o.get().clone(),
+            Entry::Vacant(v) => v
+                .insert((
+                    self.counter.fetch_add(1, Ordering::Relaxed),
+                    Arc::new(block),
+                ))
+                .clone(),
+        };
+
+        while self.blocks.read().len() > BLOCK_CACHE_SIZE {
+            let bc = self.blocks.read();
+            let (oldest, _) = bc
+                .iter()
+                .min_by(|(_, (at, _)), (_, (bt, _))| at.cmp(bt))
+                .unwrap();
+
+            let oldest = *oldest;
+            drop(bc);
+
+            self.blocks.write().remove(&oldest);
+        }
+
+        Ok(b)
+    }
+
+    /// Calls `f` with the block, borrowing it under the read lock on a cache hit.
+    /// On a miss the block is read and cached like [BlockCache::get_or_read]
+    pub fn with_block(
+        &self,
+        index: usize,
+        read_block: impl FnOnce(usize) -> anyhow::Result<Vec<u8>>,
+        f: &mut dyn FnMut(&[u8]) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        if let Some((_, b)) = self.blocks.read().get(&index) {
+            return f(b);
+        }
+
+        f(&self.get_or_read(index, read_block)?)
+    }
+
+    /// Total size of all cached blocks
+    pub fn size_bytes(&self) -> usize {
+        self.blocks.read().values().map(|(_, b)| b.len()).sum()
+    }
+}
+
+/// Strips the patch number and extension from a package path (eg. `w64_sr_audio_063c_0.pkg` => `w64_sr_audio_063c`).
+/// Patch files are resolved against the result, so a filename without a patch number is rejected
+#[cfg(feature = "reader")]
+pub(crate) fn package_path_base(path: &str) -> Result<String, PackageError> {
+    let filename_start = path.rfind(['/', '\\']).map_or(0, |i| i + 1);
+    path[filename_start..]
+        .rfind('_')
+        .map(|i| filename_start + i)
+        .filter(|&i| {
+            path[i + 1..]
+                .split('.')
+                .next()
+                .is_some_and(|patch| patch.parse::<u16>().is_ok())
+        })
+        .map(|i| path[..i].to_owned())
+        .ok_or_else(|| PackageError::UnrecognizedFilename {
+            path: path.to_owned(),
+        })
+}
+
+/// Walks the blocks of the specified entry by their actual data size, the same way [Package::read_entry] does,
+/// failing if the entry doesn't fit in the block table
+#[cfg(feature = "reader")]
+fn walk_entry_blocks<P: Package + ?Sized>(pkg: &P, index: usize) -> anyhow::Result<Vec<BlockInfo>> {
+    let entry = pkg
+        .entry(index)
+        .ok_or(anyhow!("Entry index is out of range"))?;
+
+    let mut blocks = vec![];
+    let mut covered = 0usize;
+    let mut current_block = entry.starting_block as usize;
+    while covered < entry.file_size as usize {
+        let block = pkg.block_info(current_block).ok_or_else(|| {
+            anyhow!(
+                "Entry {index} needs block {current_block}, which is out of range ({} bytes of {} covered)",
+                covered,
+                entry.file_size
+            )
+        })?;
+
+        let mut block_size = block.data_size();
+        if current_block == entry.starting_block as usize {
+            ensure!(
+                (entry.starting_block_offset as usize) < block_size,
+                "Entry {index} starts at offset 0x{:x} in block {current_block}, which only holds 0x{block_size:x} bytes",
+                entry.starting_block_offset
+            );
+            block_size -= entry.starting_block_offset as usize;
+        }
+
+        ensure!(
+            block_size != 0,
+            "Block {current_block} of entry {index} is empty"
+        );
+
+        covered += block_size;
+        current_block += 1;
+        blocks.push(block);
+    }
+
+    Ok(blocks)
+}
+
+/// Reads everything in front of the header signature, which starts at `signature_offset`
+#[cfg(feature = "reader")]
+pub(crate) fn read_raw_header(
+    reader: &RwLock<Box<dyn ReadSeek>>,
+    signature_offset: u32,
+) -> anyhow::Result<Vec<u8>> {
+    ensure!(
+        signature_offset != 0,
+        "Package does not have a header signature"
+    );
+
+    let mut data = vec![0u8; signature_offset as usize];
+    let mut reader = reader.write();
+    reader.seek(SeekFrom::Start(0))?;
+    reader.read_exact(&mut data)?;
+
+    Ok(data)
+}
+
+/// Builds the path of a patch file from the package path without its patch suffix (eg. `packages/w64_sr_gear_0426`)
+/// and the patch ID
+pub type PatchNamingFn = fn(path_base: &str, patch_id: u16) -> String;
+
+/// Options that affect how a package and its patch files are opened
+#[cfg(feature = "reader")]
+#[derive(Clone, Debug, Default)]
+pub struct PackageOpenOptions {
+    /// Secondary directory to look for patch files in, used when a patch file is not next to the package itself
+    pub patch_dir: Option<PathBuf>,
+    /// Overrides how patch file paths are built, defaults to `{path_base}_{patch_id}.pkg`
+    pub patch_naming: Option<PatchNamingFn>,
+    /// Fails to read blocks that are stored in a patch file newer than this, see [GameVersion::open_at_patch]
+    pub max_patch: Option<u16>,
+    /// Group keys registered through [PackageManager::register_key](crate::PackageManager::register_key)
+    pub keys: PackageKeys,
+    /// Reads package and patch files from this source instead of the filesystem
+    pub source: Option<Arc<dyn PackageSource>>,
+}
+
+#[cfg(feature = "reader")]
+impl PackageOpenOptions {
+    /// Returns the path of the given patch file, next to the package itself
+    pub fn patch_path(&self, path_base: &str, patch_id: u16) -> String {
+        match self.patch_naming {
+            Some(f) => f(path_base, patch_id),
+            None => format!("{path_base}_{patch_id}.pkg"),
+        }
+    }
+
+    /// Returns an error if the given block is stored in a patch file newer than `max_patch`
+    pub(crate) fn check_block_patch(
+        &self,
+        block_index: usize,
+        patch_id: u16,
+    ) -> anyhow::Result<()> {
+        if let Some(max_patch) = self.max_patch {
+            ensure!(
+                patch_id <= max_patch,
+                "Block {block_index} is only available in patch {patch_id}, which is newer than the requested patch {max_patch}"
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Opens a package file from the [source](Self::source) if one is set, or from the filesystem otherwise
+    pub(crate) fn open_file(&self, path: &str) -> anyhow::Result<SourceReader> {
+        match &self.source {
+            Some(source) => source.open(path),
+            None => Ok(Box::new(BufReader::new(
+                File::open(path).with_context(|| format!("Cannot find file '{path}'"))?,
+            ))),
+        }
+    }
+
+    /// Size of a package file in bytes, taken from the [source](Self::source) if one is set
+    pub(crate) fn file_size(&self, path: &str) -> anyhow::Result<u64> {
+        match &self.source {
+            Some(source) => source.file_size(path),
+            None => Ok(std::fs::metadata(path)
+                .with_context(|| format!("Cannot find file '{path}'"))?
+                .len()),
+        }
+    }
+
+    /// Size of the given patch file in bytes, resolved the same way as [PackageOpenOptions::open_patch_file]
+    pub(crate) fn patch_file_size(&self, path_base: &str, patch_id: u16) -> anyhow::Result<u64> {
+        let sibling_path = self.patch_path(path_base, patch_id);
+        let sibling_err = match self.file_size(&sibling_path) {
+            Ok(size) => return Ok(size),
+            Err(e) => e,
+        };
+
+        match self.patch_dir_path(&sibling_path)? {
+            Some(override_path) => self.file_size(&override_path),
+            None => Err(sibling_err),
+        }
+    }
+
+    /// Opens the given patch file for a package, looking in `patch_dir` if it isn't found next to the package.
+    /// Both locations are opened through the [source](Self::source) if one is set
+    pub(crate) fn open_patch_file(
+        &self,
+        path_base: &str,
+        patch_id: u16,
+    ) -> anyhow::Result<SourceReader> {
+        let sibling_path = self.patch_path(path_base, patch_id);
+        let sibling_err = match self.open_file(&sibling_path) {
+            Ok(f) => return Ok(f),
+            Err(e) => e,
+        };
+
+        let Some(override_path) = self.patch_dir_path(&sibling_path)? else {
+            return Err(sibling_err.context(format!("Failed to open package file {sibling_path}")));
+        };
+
+        self.open_file(&override_path).with_context(|| {
+            format!("Failed to open package file, tried {sibling_path} and {override_path}")
+        })
+    }
+
+    /// Path of the given patch file in `patch_dir` if one is set, or `None` otherwise
+    fn patch_dir_path(&self, sibling_path: &str) -> anyhow::Result<Option<String>> {
+        let Some(patch_dir) = &self.patch_dir else {
+            return Ok(None);
+        };
+
+        let filename = Path::new(sibling_path)
+            .file_name()
+            .context("Package path has no filename")?;
+        Ok(Some(patch_dir.join(filename).to_string_lossy().to_string()))
+    }
+
+    /// Checks that every patch file referenced by `patch_ids` (other than the package's own patch) can be opened
+    pub(crate) fn validate_patches(
+        &self,
+        path_base: &str,
+        current_patch_id: u16,
+        patch_ids: impl Iterator<Item = u16>,
+    ) -> anyhow::Result<()> {
+        let missing = patch_ids
+            .filter(|&p| p != current_patch_id)
+            .sorted()
+            .dedup()
+            .filter(|&p| self.open_patch_file(path_base, p).is_err())
+            .collect_vec();
+
+        if !missing.is_empty() {
+            return Err(PackageError::PatchFileMissing {
+                paths: missing
+                    .iter()
+                    .map(|&p| self.patch_path(path_base, p))
+                    .collect(),
+                path_base: path_base.to_string(),
+                patch_ids: missing,
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+}
+
 pub trait ReadSeek: Read + Seek {}
 impl<R: Read + Seek> ReadSeek for R {}
 
@@ -28,6 +357,63 @@ pub struct UEntryHeader {
     pub starting_block: u32,
     pub starting_block_offset: u32,
     pub file_size: u32,
+    /// Type info word as stored in the entry table, `file_type` and `file_subtype` are decoded from this.
+    /// Its layout differs per format, so this is mainly useful for researching new formats
+    pub raw_type_info: u32,
+    /// Block info as stored in the entry table, the block, offset and size fields are decoded from this
+    pub raw_block_info: u64,
+}
+
+/// Describes how a block is stored on disk
+#[derive(Clone, Debug)]
+pub struct BlockInfo {
+    pub index: usize,
+    /// The patch file the block is stored in
+    pub patch_id: u16,
+    pub flags: u16,
+    /// Size of the block on disk, before decryption and decompression
+    pub size: u32,
+    /// Compressed blocks always decompress to [BLOCK_SIZE] bytes
+    pub compressed: bool,
+}
+
+impl BlockInfo {
+    /// Amount of bytes this block holds once decrypted and decompressed
+    pub fn data_size(&self) -> usize {
+        if self.compressed {
+            BLOCK_SIZE
+        } else {
+            self.size as usize
+        }
+    }
+}
+
+/// Key a block is encrypted with, see [Package::block_cipher_kind]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CipherKind {
+    /// The block isn't encrypted
+    None,
+    /// Built-in key, used by most encrypted blocks
+    Default0,
+    /// Built-in key, used by blocks with flag 0x4
+    Default1,
+    /// External key for the given package group, which has to be provided through keys.txt
+    External(u64),
+}
+
+impl CipherKind {
+    /// Determines the key used for a Destiny 2 block from its flags
+    pub fn from_block_flags(flags: u16, group_id: u64) -> Self {
+        if (flags & 0x2) == 0 {
+            CipherKind::None
+        } else if (flags & 0x8) != 0 {
+            CipherKind::External(group_id)
+        } else if (flags & 0x4) != 0 {
+            CipherKind::Default1
+        } else {
+            CipherKind::Default0
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -37,7 +423,7 @@ pub struct UHashTableEntry {
     pub reference: TagHash,
 }
 
-#[derive(BinRead, Debug, Copy, Clone)]
+#[derive(BinRead, Debug, Copy, Clone, PartialEq, Eq, Hash)]
 #[br(repr = u16)]
 pub enum PackageLanguage {
     None = 0,
@@ -48,16 +434,74 @@ pub enum PackageLanguage {
     Spanish = 5,
     Japanese = 6,
     Portuguese = 7,
+
+    // The following languages are only used by Destiny 2, which doesn't store the language in the package header
+    Russian = 8,
+    Polish = 9,
+    Korean = 10,
+    /// Latin American Spanish, not to be confused with [PackageLanguage::Spanish]
+    SpanishLatAm = 11,
+    /// Not to be confused with [PackageLanguage::ChineseTraditional]
+    ChineseSimplified = 12,
+    /// Not to be confused with [PackageLanguage::ChineseSimplified]
+    ChineseTraditional = 13,
 }
 
 impl PackageLanguage {
     pub fn english_or_none(&self) -> bool {
         matches!(self, Self::None | Self::English)
     }
+
+    /// The language code as used in package filenames, or an empty string for [PackageLanguage::None].
+    ///
+    /// Spanish and Chinese each have two variants, which use their regional codes:
+    /// `es` (Spain) and `mx` (Latin America), `cs` (Simplified) and `ct` (Traditional)
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::None => "",
+            Self::English => "en",
+            Self::French => "fr",
+            Self::Italian => "it",
+            Self::German => "de",
+            Self::Spanish => "es",
+            Self::Japanese => "ja",
+            Self::Portuguese => "pt",
+            Self::Russian => "ru",
+            Self::Polish => "pl",
+            Self::Korean => "ko",
+            Self::SpanishLatAm => "mx",
+            Self::ChineseSimplified => "cs",
+            Self::ChineseTraditional => "ct",
+        }
+    }
+
+    /// Maps a language code as found in package filenames to a language.
+    /// This is the inverse of [PackageLanguage::code], but also accepts the common IETF-style codes for the
+    /// ambiguous languages (`es-mx`, `zh-chs`/`zh-hans` and `zh-cht`/`zh-hant`).
+    /// Bare `zh` is rejected, as it could be either variant
+    pub fn from_code(code: &str) -> Option<Self> {
+        Some(match code.to_lowercase().as_str() {
+            "" => Self::None,
+            "en" => Self::English,
+            "fr" => Self::French,
+            "it" => Self::Italian,
+            "de" => Self::German,
+            "es" => Self::Spanish,
+            "ja" => Self::Japanese,
+            "pt" => Self::Portuguese,
+            "ru" => Self::Russian,
+            "pl" => Self::Polish,
+            "ko" => Self::Korean,
+            "mx" | "es-mx" => Self::SpanishLatAm,
+            "cs" | "zh-chs" | "zh-hans" => Self::ChineseSimplified,
+            "ct" | "zh-cht" | "zh-hant" => Self::ChineseTraditional,
+            _ => return None,
+        })
+    }
 }
 
 #[derive(
-    serde::Serialize, serde::Deserialize, clap::ValueEnum, PartialEq, PartialOrd, Debug, Clone, Copy,
+    serde::Serialize, serde::Deserialize, clap::ValueEnum, PartialEq, Eq, Hash, Debug, Clone, Copy,
 )]
 pub enum GameVersion {
     /// X360 december 2013 internal alpha version of Destiny
@@ -97,29 +541,100 @@ pub enum GameVersion {
 
     #[value(name = "d2_tfs")]
     Destiny2TheFinalShape = 8000,
+
+    /// Destiny 2 (Episodes: Echoes/Revenant/Heresy)
+    #[value(name = "d2_eps")]
+    Destiny2Episodes = 8500,
+}
+
+/// Versions are ordered by release, see [GameVersion::release_index]
+impl Ord for GameVersion {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.release_index().cmp(&other.release_index())
+    }
 }
 
+impl PartialOrd for GameVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(feature = "reader")]
 impl GameVersion {
     pub fn open(&self, path: &str) -> anyhow::Result<Arc<dyn Package>> {
+        self.open_with_options(path, &PackageOpenOptions::default())
+    }
+
+    pub fn open_with_options(
+        &self,
+        path: &str,
+        options: &PackageOpenOptions,
+    ) -> anyhow::Result<Arc<dyn Package>> {
         Ok(match self {
-            GameVersion::DestinyInternalAlpha => Arc::new(PackageD1InternalAlpha::open(path)?),
-            GameVersion::DestinyTheTakenKing => Arc::new(PackageD1Legacy::open(path)?),
-            GameVersion::DestinyRiseOfIron => Arc::new(PackageD1RiseOfIron::open(path)?),
-            GameVersion::Destiny2Beta => Arc::new(PackageD2Beta::open(path)?),
+            GameVersion::DestinyInternalAlpha => {
+                Arc::new(PackageD1InternalAlpha::open(path, options)?)
+            }
+            GameVersion::DestinyTheTakenKing => Arc::new(PackageD1Legacy::open(path, options)?),
+            GameVersion::DestinyRiseOfIron => Arc::new(PackageD1RiseOfIron::open(path, options)?),
+            GameVersion::Destiny2Beta => Arc::new(PackageD2Beta::open(path, options)?),
 
             GameVersion::Destiny2Forsaken | GameVersion::Destiny2Shadowkeep => {
-                Arc::new(PackageD2PreBL::open(path)?)
+                Arc::new(PackageD2PreBL::open_with_options(path, options)?)
             }
 
             GameVersion::Destiny2BeyondLight
             | GameVersion::Destiny2WitchQueen
             | GameVersion::Destiny2Lightfall
-            | GameVersion::Destiny2TheFinalShape => {
-                Arc::new(PackageD2BeyondLight::open(path, *self)?)
+            | GameVersion::Destiny2TheFinalShape
+            | GameVersion::Destiny2Episodes => {
+                Arc::new(PackageD2BeyondLight::open(path, *self, options)?)
             }
         })
     }
 
+    /// Opens a package as it was at the given patch, for comparing content across game updates.
+    /// Opens the newest patch file of the package that isn't newer than `max_patch`, and refuses to read any blocks
+    /// that are only stored in newer patch files
+    pub fn open_at_patch(&self, path: &str, max_patch: u16) -> anyhow::Result<Arc<dyn Package>> {
+        self.open_at_patch_with_options(path, max_patch, &PackageOpenOptions::default())
+    }
+
+    /// Same as [GameVersion::open_at_patch], but looks up the patch files through the given options, so they can come
+    /// from `patch_dir` or a custom [source](PackageOpenOptions::source)
+    pub fn open_at_patch_with_options(
+        &self,
+        path: &str,
+        max_patch: u16,
+        options: &PackageOpenOptions,
+    ) -> anyhow::Result<Arc<dyn Package>> {
+        let options = PackageOpenOptions {
+            max_patch: Some(max_patch),
+            ..options.clone()
+        };
+
+        let path_base = package_path_base(path)?;
+        let patch_path = (0..=max_patch)
+            .rev()
+            .find_map(|p| {
+                let sibling_path = options.patch_path(&path_base, p);
+                if options.file_size(&sibling_path).is_ok() {
+                    return Some(sibling_path);
+                }
+
+                let override_path = options.patch_dir_path(&sibling_path).ok()??;
+                options
+                    .file_size(&override_path)
+                    .is_ok()
+                    .then_some(override_path)
+            })
+            .with_context(|| format!("No patch file up to patch {max_patch} exists for {path}"))?;
+
+        self.open_with_options(&patch_path, &options)
+    }
+}
+
+impl GameVersion {
     pub fn endian(&self) -> Endian {
         match self {
             GameVersion::DestinyInternalAlpha | GameVersion::DestinyTheTakenKing => Endian::Big,
@@ -140,13 +655,72 @@ impl GameVersion {
         matches!(
             self,
             GameVersion::Destiny2Beta
+                | GameVersion::Destiny2Forsaken
                 | GameVersion::Destiny2Shadowkeep
                 | GameVersion::Destiny2BeyondLight
                 | GameVersion::Destiny2WitchQueen
                 | GameVersion::Destiny2Lightfall
+                | GameVersion::Destiny2TheFinalShape
+                | GameVersion::Destiny2Episodes
+        )
+    }
+
+    /// Destiny 2 before Beyond Light, including the beta
+    pub fn is_prebl(&self) -> bool {
+        matches!(
+            self,
+            GameVersion::Destiny2Beta
+                | GameVersion::Destiny2Forsaken
+                | GameVersion::Destiny2Shadowkeep
         )
     }
 
+    /// Position of the version in release order, oldest first. The discriminants don't follow release order, so this
+    /// is what versions are compared by
+    pub fn release_index(&self) -> usize {
+        match self {
+            GameVersion::DestinyInternalAlpha => 0,
+            GameVersion::DestinyTheTakenKing => 1,
+            GameVersion::DestinyRiseOfIron => 2,
+            GameVersion::Destiny2Beta => 3,
+            GameVersion::Destiny2Forsaken => 4,
+            GameVersion::Destiny2Shadowkeep => 5,
+            GameVersion::Destiny2BeyondLight => 6,
+            GameVersion::Destiny2WitchQueen => 7,
+            GameVersion::Destiny2Lightfall => 8,
+            GameVersion::Destiny2TheFinalShape => 9,
+            GameVersion::Destiny2Episodes => 10,
+        }
+    }
+
+    /// Destiny 2 from Beyond Light onwards
+    pub fn is_postbl(&self) -> bool {
+        matches!(
+            self,
+            GameVersion::Destiny2BeyondLight
+                | GameVersion::Destiny2WitchQueen
+                | GameVersion::Destiny2Lightfall
+                | GameVersion::Destiny2TheFinalShape
+                | GameVersion::Destiny2Episodes
+        )
+    }
+
+    pub fn is_alpha_or_beta(&self) -> bool {
+        matches!(
+            self,
+            GameVersion::DestinyInternalAlpha | GameVersion::Destiny2Beta
+        )
+    }
+
+    /// The Oodle version used to compress blocks
+    pub fn oodle_version(&self) -> OodleVersion {
+        if self.is_postbl() {
+            OodleVersion::V9
+        } else {
+            OodleVersion::V3
+        }
+    }
+
     pub fn id(&self) -> String {
         self.to_possible_value()
             .expect("Package version is missing an id/commandline value")
@@ -166,16 +740,35 @@ impl GameVersion {
             GameVersion::Destiny2WitchQueen => "Destiny 2: Witch Queen",
             GameVersion::Destiny2Lightfall => "Destiny 2: Lightfall",
             GameVersion::Destiny2TheFinalShape => "Destiny 2: The Final Shape",
+            GameVersion::Destiny2Episodes => "Destiny 2: Episodes",
         }
     }
 }
 
 // TODO(cohae): Package language
+#[cfg(feature = "reader")]
 pub trait Package: Send + Sync {
     fn endianness(&self) -> binrw::Endian;
 
-    fn pkg_id(&self) -> u16;
-    fn patch_id(&self) -> u16;
+    fn pkg_id(&self) -> PkgId;
+    fn patch_id(&self) -> PatchId;
+
+    /// Version word at the start of the package header (eg. 53 for Beyond Light and newer).
+    /// Versions that share a number can still differ in layout, so combine this with [Package::endianness] to tell
+    /// formats apart
+    fn format_version(&self) -> u16;
+
+    /// Group used to look up external keys for encrypted blocks.
+    /// Does not apply to Destiny 1
+    fn group_id(&self) -> Option<u64> {
+        None
+    }
+
+    /// Content version (major, minor) stored in the header.
+    /// Only Rise of Iron packages have one, which tells apart the different patch generations of that release
+    fn content_version(&self) -> Option<(u16, u16)> {
+        None
+    }
 
     /// Every hash64 in this package.
     /// Does not apply to Destiny 1
@@ -185,65 +778,194 @@ pub trait Package: Send + Sync {
 
     fn entries(&self) -> &[UEntryHeader];
 
+    /// Shared handle to the entries of this package, if the implementation keeps them in an [Arc].
+    /// Lets the manager index entries without copying them
+    fn entries_arc(&self) -> Option<Arc<[UEntryHeader]>> {
+        None
+    }
+
     fn entry(&self, index: usize) -> Option<UEntryHeader>;
 
+    /// Every entry in this package, paired with its tag hash
+    fn iter_tags(&self) -> Box<dyn Iterator<Item = (TagHash, &UEntryHeader)> + '_> {
+        let pkg_id = self.pkg_id();
+        Box::new(
+            self.entries()
+                .iter()
+                .enumerate()
+                .map(move |(i, e)| (TagHash::new(pkg_id, i as _), e)),
+        )
+    }
+
     fn language(&self) -> PackageLanguage {
         PackageLanguage::None
     }
 
+    /// Size of the package file in bytes, as stored in the header
+    fn file_size(&self) -> u64;
+
+    /// Raw bytes of the package header, up to the header signature.
+    /// Meant for research, the signature itself is not verified
+    fn raw_header(&self) -> anyhow::Result<Vec<u8>> {
+        Err(anyhow!(
+            "Raw header access is not supported for this package format"
+        ))
+    }
+
     /// Gets/reads a specific block from the file.
     /// It's recommended that the implementation caches blocks to prevent re-reads
     fn get_block(&self, index: usize) -> anyhow::Result<Arc<Vec<u8>>>;
 
-    /// Reads the entire specified entry's data
+    /// Calls `f` with the data of a specific block, borrowing it straight from the block cache if it's cached.
+    /// Avoids the [Arc] churn of [Package::get_block] for callers that only look at a block briefly, which adds up on
+    /// packages that are mostly uncompressed data.
+    /// The block cache may be locked while `f` runs, so `f` must not read from this package
+    fn with_block(
+        &self,
+        index: usize,
+        f: &mut dyn FnMut(&[u8]) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        f(&self.get_block(index)?)
+    }
+
+    /// Returns the block header for a specific block
+    fn block_info(&self, index: usize) -> Option<BlockInfo>;
+
+    /// Returns the key the specified block is encrypted with.
+    /// Destiny 1 packages and out of range blocks always return [CipherKind::None]
+    fn block_cipher_kind(&self, block_index: usize) -> CipherKind {
+        match (self.block_info(block_index), self.group_id()) {
+            (Some(b), Some(group_id)) => CipherKind::from_block_flags(b.flags, group_id),
+            _ => CipherKind::None,
+        }
+    }
+
+    /// Returns every block the specified entry's data is stored in
+    fn entry_block_layout(&self, index: usize) -> anyhow::Result<Vec<BlockInfo>> {
+        walk_entry_blocks(self, index)
+    }
+
+    /// Checks that the blocks of the specified entry are within the block table and hold at least `file_size` bytes,
+    /// without reading any block data. Useful for catching malformed entries before reading them
+    fn validate_entry(&self, index: usize) -> anyhow::Result<()> {
+        walk_entry_blocks(self, index).map(|_| ())
+    }
+
+    /// Checks that every patch file referenced by the block table exists and can be opened
+    fn validate_patches(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Reads a specific block exactly as it is stored on disk.
+    /// The returned data is still encrypted and/or compressed, depending on the block flags
+    fn get_block_raw_bytes(&self, index: usize) -> anyhow::Result<Vec<u8>>;
+
+    /// Reads, decrypts and decompresses a specific block without going through the block cache.
+    /// The result is never cached, so prefer [Package::get_block] unless the current data on disk is needed
+    fn read_block_uncached(&self, index: usize) -> anyhow::Result<Vec<u8>>;
+
+    /// Total size of all decompressed blocks currently held in the block cache
+    fn block_cache_bytes(&self) -> usize;
+
+    /// Reads the entire specified entry's data.
+    /// Empty entries return `Ok(vec![])`, their starting block isn't necessarily valid so it's never read
     fn read_entry(&self, index: usize) -> anyhow::Result<Vec<u8>> {
         let _span = tracing::debug_span!("Package::read_entry").entered();
         let entry = self
             .entry(index)
             .ok_or(anyhow!("Entry index is out of range"))?;
 
+        if entry.file_size == 0 {
+            return Ok(vec![]);
+        }
+
         let mut buffer = Vec::with_capacity(entry.file_size as usize);
-        let mut current_offset = 0usize;
-        let mut current_block = entry.starting_block;
+        // Copying into the buffer never touches the package, so blocks can be borrowed from the cache
+        read_entry_blocks(&entry, &mut |i, f| self.with_block(i, f), &mut |chunk| {
+            buffer.extend_from_slice(chunk);
+            Ok(())
+        })?;
 
-        while current_offset < entry.file_size as usize {
-            let remaining_bytes = entry.file_size as usize - current_offset;
-            let block_data = self.get_block(current_block as usize)?;
+        Ok(buffer)
+    }
 
-            if current_block == entry.starting_block {
-                let block_start_offset = entry.starting_block_offset as usize;
-                let block_remaining = block_data.len() - block_start_offset;
-                let copy_size = if block_remaining < remaining_bytes {
-                    block_remaining
-                } else {
-                    remaining_bytes
-                };
+    /// Reads every entry in the package, returning the results in entry index order.
+    /// Entries are read in block order so blocks shared by multiple small entries only get decompressed once
+    fn read_all_entries(&self) -> Vec<anyhow::Result<Vec<u8>>> {
+        let entries = self.entries();
+        let read_order = (0..entries.len())
+            .sorted_by_key(|&i| (entries[i].starting_block, entries[i].starting_block_offset))
+            .collect_vec();
 
-                buffer.extend_from_slice(
-                    &block_data[block_start_offset..block_start_offset + copy_size],
-                );
+        let mut results: Vec<Option<anyhow::Result<Vec<u8>>>> =
+            (0..entries.len()).map(|_| None).collect();
+        for i in read_order {
+            results[i] = Some(self.read_entry(i));
+        }
 
-                current_offset += copy_size;
-            } else if remaining_bytes < block_data.len() {
-                // If the block has more bytes than we need, it means we're on the last block
-                buffer.extend_from_slice(&block_data[..remaining_bytes]);
-                current_offset += remaining_bytes;
-            } else {
-                // If the previous 2 conditions failed, it means this whole block belongs to the file
-                buffer.extend_from_slice(&block_data[..]);
-                current_offset += block_data.len();
-            }
+        results.into_iter().flatten().collect()
+    }
 
-            current_block += 1;
+    /// Same as [Package::read_entry], but reads every block straight from disk with [Package::read_block_uncached].
+    /// Blocks shared with other entries are decompressed again and the block cache is neither used nor updated, so
+    /// this is considerably slower. Useful when the package file may have changed on disk since blocks were cached
+    fn read_entry_uncached(&self, index: usize) -> anyhow::Result<Vec<u8>> {
+        let entry = self
+            .entry(index)
+            .ok_or(anyhow!("Entry index is out of range"))?;
+
+        if entry.file_size == 0 {
+            return Ok(vec![]);
         }
 
+        let mut buffer = Vec::with_capacity(entry.file_size as usize);
+        read_entry_blocks(
+            &entry,
+            &mut |i, f| f(&self.read_block_uncached(i)?),
+            &mut |chunk| {
+                buffer.extend_from_slice(chunk);
+                Ok(())
+            },
+        )?;
+
         Ok(buffer)
     }
 
+    /// Writes the specified entry's data to `w` block by block, without buffering the entire entry.
+    /// Returns the amount of bytes written
+    fn copy_entry_to(&self, index: usize, w: &mut dyn Write) -> anyhow::Result<u64> {
+        let mut written = 0u64;
+        self.read_entry_chunked(index, &mut |chunk| {
+            w.write_all(chunk)?;
+            written += chunk.len() as u64;
+            Ok(())
+        })?;
+
+        Ok(written)
+    }
+
+    /// Passes the specified entry's data to `f` one block-sized chunk at a time, without buffering the entire entry.
+    /// `f` is never called for empty entries
+    fn read_entry_chunked(
+        &self,
+        index: usize,
+        f: &mut dyn FnMut(&[u8]) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let entry = self
+            .entry(index)
+            .ok_or(anyhow!("Entry index is out of range"))?;
+
+        if entry.file_size == 0 {
+            return Ok(());
+        }
+
+        read_entry_blocks(&entry, &mut |i, f| f(&self.get_block(i)?), f)
+    }
+
     /// Reads the entire specified entry's data
     /// Tag needs to be in this package
     fn read_tag(&self, tag: TagHash) -> anyhow::Result<Vec<u8>> {
-        ensure!(tag.pkg_id() == self.pkg_id());
+        ensure!(PkgId(tag.pkg_id()) == self.pkg_id());
         self.read_entry(tag.entry_index() as _)
     }
 
@@ -269,6 +991,14 @@ pub trait Package: Send + Sync {
             .collect()
     }
 
+    /// Same as [Package::get_all_by_reference], but pairs every entry with its tag hash instead of its index
+    fn tags_by_reference(&self, reference: u32) -> Vec<(TagHash, UEntryHeader)> {
+        self.iter_tags()
+            .filter(|(_, e)| e.reference == reference)
+            .map(|(t, e)| (t, e.clone()))
+            .collect()
+    }
+
     fn get_all_by_type(&self, etype: u8, esubtype: Option<u8>) -> Vec<(usize, UEntryHeader)> {
         self.entries()
             .iter()
@@ -281,6 +1011,66 @@ pub trait Package: Send + Sync {
     }
 }
 
+/// Callback that passes the data of the given block to the inner callback
+#[cfg(feature = "reader")]
+type WithBlockFn<'a> =
+    dyn FnMut(usize, &mut dyn FnMut(&[u8]) -> anyhow::Result<()>) -> anyhow::Result<()> + 'a;
+
+/// Passes the data of `entry` to `f` one block at a time, getting the data of each block through `with_block`
+#[cfg(feature = "reader")]
+fn read_entry_blocks(
+    entry: &UEntryHeader,
+    with_block: &mut WithBlockFn,
+    f: &mut dyn FnMut(&[u8]) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut current_offset = 0usize;
+    let mut current_block = entry.starting_block;
+
+    while current_offset < entry.file_size as usize {
+        let remaining_bytes = entry.file_size as usize - current_offset;
+        with_block(current_block as usize, &mut |block_data| {
+            if current_block == entry.starting_block {
+                let block_start_offset = entry.starting_block_offset as usize;
+                let block_remaining = block_data.len() - block_start_offset;
+                let copy_size = if block_remaining < remaining_bytes {
+                    block_remaining
+                } else {
+                    remaining_bytes
+                };
+
+                f(&block_data[block_start_offset..block_start_offset + copy_size])?;
+
+                current_offset += copy_size;
+            } else if remaining_bytes < block_data.len() {
+                // If the block has more bytes than we need, it means we're on the last block
+                f(&block_data[..remaining_bytes])?;
+                current_offset += remaining_bytes;
+            } else {
+                // If the previous 2 conditions failed, it means this whole block belongs to the file
+                f(block_data)?;
+                current_offset += block_data.len();
+            }
+
+            Ok(())
+        })?;
+
+        current_block += 1;
+    }
+
+    Ok(())
+}
+
+/// Guesses a file extension for an entry based on its type and subtype.
+/// Versions without a known classification always return "bin"
+pub fn classify_file(version: GameVersion, ftype: u8, fsubtype: u8) -> String {
+    match version {
+        GameVersion::Destiny2Forsaken | GameVersion::Destiny2Shadowkeep => {
+            classify_file_prebl(ftype, fsubtype)
+        }
+        _ => "bin".to_string(),
+    }
+}
+
 /// ! Currently only works for Pre-BL Destiny 2
 pub fn classify_file_prebl(ftype: u8, fsubtype: u8) -> String {
     match (ftype, fsubtype) {
@@ -362,3 +1152,196 @@ impl Display for PackagePlatform {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use clap::ValueEnum;
+
+    use super::GameVersion;
+    #[cfg(feature = "reader")]
+    use std::{sync::Arc, time::Instant};
+
+    #[cfg(feature = "reader")]
+    use crate::{test_util, PackageOpenOptions, PatchId, TagHash};
+
+    #[test]
+    fn versions_are_ordered_by_release() {
+        let chronological = [
+            GameVersion::DestinyInternalAlpha,
+            GameVersion::DestinyTheTakenKing,
+            GameVersion::DestinyRiseOfIron,
+            GameVersion::Destiny2Beta,
+            GameVersion::Destiny2Forsaken,
+            GameVersion::Destiny2Shadowkeep,
+            GameVersion::Destiny2BeyondLight,
+            GameVersion::Destiny2WitchQueen,
+            GameVersion::Destiny2Lightfall,
+            GameVersion::Destiny2TheFinalShape,
+            GameVersion::Destiny2Episodes,
+        ];
+        assert_eq!(chronological.len(), GameVersion::value_variants().len());
+
+        for (i, a) in chronological.iter().enumerate() {
+            assert_eq!(a.release_index(), i);
+            for b in &chronological[i + 1..] {
+                assert!(a < b, "{a:?} should be older than {b:?}");
+            }
+        }
+
+        let mut sorted = GameVersion::value_variants().to_vec();
+        sorted.reverse();
+        sorted.sort();
+        assert_eq!(sorted, chronological);
+        assert!(GameVersion::DestinyInternalAlpha < GameVersion::Destiny2WitchQueen);
+    }
+
+    #[cfg(feature = "reader")]
+    #[test]
+    fn tags_by_reference_returns_every_match() {
+        let mut writer = test_util::writer(0x123);
+        let first = writer.add_entry(0x80801234, 8, 0, b"a".to_vec()).unwrap();
+        writer.add_entry(0x80805678, 8, 0, b"b".to_vec()).unwrap();
+        let second = writer.add_entry(0x80801234, 8, 0, b"c".to_vec()).unwrap();
+
+        let package = test_util::open_bytes(test_util::write(&writer));
+        let tags = package
+            .tags_by_reference(0x80801234)
+            .into_iter()
+            .map(|(t, _)| t)
+            .collect::<Vec<_>>();
+        assert_eq!(tags, [first, second]);
+        assert_eq!(tags, [TagHash::new(0x123, 0), TagHash::new(0x123, 2)]);
+    }
+
+    #[cfg(feature = "reader")]
+    #[test]
+    fn empty_entries_read_without_blocks() {
+        // An empty entry at the end of the package starts past the last block
+        let data = test_util::package_bytes(0x123, &[(0x80800000, &[0x11; 32]), (0x80800000, &[])]);
+        let package = test_util::open_bytes(data);
+
+        assert_eq!(package.read_entry(1).unwrap(), b"");
+        assert_eq!(package.read_entry_uncached(1).unwrap(), b"");
+        assert_eq!(package.read_entry_uncached(0).unwrap(), [0x11; 32]);
+    }
+
+    #[cfg(feature = "reader")]
+    #[test]
+    fn open_at_patch_resolves_patches_through_source() {
+        let mut files = test_util::MemoryFiles::default();
+        let patch_path = |patch: u16| format!("w64_test_0123_{patch}.pkg");
+        files.0.insert(
+            patch_path(0),
+            test_util::package_bytes(0x123, &[(0x80800000, b"0")]),
+        );
+
+        // Patch 1 only exists in patch_dir, which lives in the source too
+        let mut writer = test_util::writer(0x123);
+        writer.patch_id = 1;
+        writer.add_entry(0x80800000, 8, 0, b"1".to_vec()).unwrap();
+        files.0.insert(
+            format!("patches/{}", patch_path(1)),
+            test_util::write(&writer),
+        );
+
+        let options = PackageOpenOptions {
+            source: Some(Arc::new(files)),
+            patch_dir: Some("patches".into()),
+            ..Default::default()
+        };
+
+        let version = test_util::TEST_VERSION;
+        let package = version
+            .open_at_patch_with_options(&patch_path(0), 0, &options)
+            .unwrap();
+        assert_eq!(package.patch_id(), PatchId(0));
+        assert_eq!(package.read_entry(0).unwrap(), b"0");
+
+        let package = version
+            .open_at_patch_with_options(&patch_path(0), 3, &options)
+            .unwrap();
+        assert_eq!(package.patch_id(), PatchId(1));
+        assert_eq!(package.read_entry(0).unwrap(), b"1");
+
+        // Without the source, neither patch file exists on disk
+        assert!(version.open_at_patch(&patch_path(0), 3).is_err());
+    }
+
+    /// Reads many small tags from one package on several threads, once through the block cache and once straight from
+    /// the reader. Cached reads don't take the reader lock, so they should scale with the thread count while uncached
+    /// reads serialize on it.
+    /// Run with `cargo test --release -- --ignored --nocapture parallel_cached_reads`
+    #[cfg(feature = "reader")]
+    #[test]
+    #[ignore = "benchmark"]
+    fn parallel_cached_reads() {
+        const ENTRIES: usize = 512;
+        const ROUNDS: usize = 200;
+
+        let data: Vec<u8> = (0..64).collect();
+        let entries = vec![(0x80800000, data.as_slice()); ENTRIES];
+        let package = test_util::open_bytes(test_util::package_bytes(0x123, &entries));
+
+        // Fill the block cache
+        for i in 0..ENTRIES {
+            package.read_entry(i).unwrap();
+        }
+
+        let run = |threads: usize, read: &(dyn Fn(usize) -> Vec<u8> + Sync)| {
+            let start = Instant::now();
+            std::thread::scope(|s| {
+                for _ in 0..threads {
+                    s.spawn(|| {
+                        for _ in 0..ROUNDS {
+                            for i in 0..ENTRIES {
+                                assert_eq!(read(i).len(), 64);
+                            }
+                        }
+                    });
+                }
+            });
+
+            let elapsed = start.elapsed();
+            let reads = threads * ROUNDS * ENTRIES;
+            println!(
+                "{threads} thread(s): {reads} reads in {elapsed:?} ({:.0} reads/s)",
+                reads as f64 / elapsed.as_secs_f64()
+            );
+        };
+
+        for threads in [1, 2, 4, 8] {
+            print!("cached, ");
+            run(threads, &|i| package.read_entry(i).unwrap());
+            print!("uncached, ");
+            run(threads, &|i| package.read_entry_uncached(i).unwrap());
+        }
+    }
+
+    #[cfg(feature = "reader")]
+    #[test]
+    fn entry_block_layout_follows_block_sizes() {
+        let first = vec![0x11; 0x2000];
+        let second = vec![0x22; super::BLOCK_SIZE];
+        let mut data =
+            test_util::package_bytes(0x123, &[(0x80800000, &first), (0x80800000, &second)]);
+
+        let package = test_util::open_bytes(data.clone());
+        let indices = |p: &dyn super::Package, i| {
+            p.entry_block_layout(i)
+                .unwrap()
+                .iter()
+                .map(|b| b.index)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(indices(package.as_ref(), 0), [0]);
+        assert_eq!(indices(package.as_ref(), 1), [0, 1]);
+
+        // Shrink the first block, so the first entry spills into the second one
+        let block_table_offset = u32::from_le_bytes(data[0x6c..0x70].try_into().unwrap()) as usize;
+        data[block_table_offset + 4..block_table_offset + 8]
+            .copy_from_slice(&0x1000u32.to_le_bytes());
+        let package = test_util::open_bytes(data);
+        assert_eq!(indices(package.as_ref(), 0), [0, 1]);
+        package.validate_entry(0).unwrap();
+    }
+}
@@ -0,0 +1,104 @@
+use std::{
+    fmt::Debug,
+    io::{Seek, SeekFrom},
+};
+#[cfg(feature = "zip")]
+use std::{
+    fs::File,
+    io::{BufReader, Cursor, Read},
+    path::Path,
+};
+
+#[cfg(feature = "zip")]
+use anyhow::Context;
+#[cfg(feature = "zip")]
+use parking_lot::Mutex;
+
+use crate::package::ReadSeek;
+
+/// Reader returned by [PackageSource::open]
+pub type SourceReader = Box<dyn ReadSeek + Send>;
+
+/// Somewhere other than the filesystem that package files can be read from, see
+/// [PackageManagerBuilder::source](crate::manager::PackageManagerBuilder::source).
+/// Paths handed to the source are the ones it returned from [PackageSource::list_packages], patch files are resolved
+/// by replacing the patch number in those paths
+pub trait PackageSource: Send + Sync + Debug {
+    /// Paths of every package file available in this source
+    fn list_packages(&self) -> anyhow::Result<Vec<String>>;
+
+    /// Opens a file for reading, failing if it doesn't exist
+    fn open(&self, path: &str) -> anyhow::Result<SourceReader>;
+
+    /// Size of a file in bytes, failing if it doesn't exist.
+    /// The default implementation opens the file and seeks to the end
+    fn file_size(&self, path: &str) -> anyhow::Result<u64> {
+        Ok(self.open(path)?.seek(SeekFrom::End(0))?)
+    }
+}
+
+/// Reads packages straight from a zip archive of a packages directory, without extracting it.
+///
+/// Zip entries can't be seeked, so every package file is decompressed into memory in full when it's opened. Each open
+/// package (and each patch file it reads from) keeps its whole file in memory until it's closed, which can add up to
+/// several gigabytes for an entire install. Indexing only keeps a few packages open at a time
+#[cfg(feature = "zip")]
+pub struct ZipSource {
+    archive: Mutex<zip::ZipArchive<BufReader<File>>>,
+}
+
+#[cfg(feature = "zip")]
+impl ZipSource {
+    pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open zip archive {}", path.display()))?;
+        let archive = zip::ZipArchive::new(BufReader::new(file))
+            .with_context(|| format!("Failed to read zip archive {}", path.display()))?;
+
+        Ok(Self {
+            archive: Mutex::new(archive),
+        })
+    }
+}
+
+#[cfg(feature = "zip")]
+impl Debug for ZipSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ZipSource({} entries)", self.archive.lock().len())
+    }
+}
+
+#[cfg(feature = "zip")]
+impl PackageSource for ZipSource {
+    fn list_packages(&self) -> anyhow::Result<Vec<String>> {
+        Ok(self
+            .archive
+            .lock()
+            .file_names()
+            .filter(|n| n.to_lowercase().ends_with(".pkg"))
+            .map(str::to_string)
+            .collect())
+    }
+
+    fn open(&self, path: &str) -> anyhow::Result<SourceReader> {
+        let mut archive = self.archive.lock();
+        let mut entry = archive
+            .by_name(path)
+            .with_context(|| format!("File {path} not found in zip archive"))?;
+
+        let mut data = Vec::with_capacity(entry.size() as usize);
+        entry.read_to_end(&mut data)?;
+
+        Ok(Box::new(Cursor::new(data)))
+    }
+
+    fn file_size(&self, path: &str) -> anyhow::Result<u64> {
+        Ok(self
+            .archive
+            .lock()
+            .by_name(path)
+            .with_context(|| format!("File {path} not found in zip archive"))?
+            .size())
+    }
+}
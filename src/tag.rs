@@ -5,6 +5,17 @@ use std::{
 
 use binrw::{BinRead, BinWrite};
 
+/// Reference to an entry in a package, encoded as `0x80800000 + (pkg_id << 13) + entry_index`.
+/// This gives 13 bits for the entry index and 10 bits for the package ID.
+///
+/// Every supported version uses this layout:
+/// - Destiny: [Internal Alpha](crate::GameVersion::DestinyInternalAlpha),
+///   [The Taken King](crate::GameVersion::DestinyTheTakenKing), [Rise of Iron](crate::GameVersion::DestinyRiseOfIron)
+/// - Destiny 2 before Beyond Light: [Beta](crate::GameVersion::Destiny2Beta),
+///   [Forsaken](crate::GameVersion::Destiny2Forsaken), [Shadowkeep](crate::GameVersion::Destiny2Shadowkeep)
+/// - Destiny 2 from Beyond Light onwards: [Beyond Light](crate::GameVersion::Destiny2BeyondLight),
+///   [Witch Queen](crate::GameVersion::Destiny2WitchQueen), [Lightfall](crate::GameVersion::Destiny2Lightfall),
+///   [The Final Shape](crate::GameVersion::Destiny2TheFinalShape), [Episodes](crate::GameVersion::Destiny2Episodes)
 #[derive(
     BinRead,
     BinWrite,
@@ -38,6 +49,56 @@ impl From<(u16, u16)> for TagHash {
     }
 }
 
+/// ID of a package, as stored in its header and encoded in every [TagHash] pointing into it
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PkgId(pub u16);
+
+/// Patch number of a package file, the `N` in `_N.pkg`
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PatchId(pub u16);
+
+impl From<u16> for PkgId {
+    fn from(value: u16) -> Self {
+        Self(value)
+    }
+}
+
+impl From<PkgId> for u16 {
+    fn from(value: PkgId) -> Self {
+        value.0
+    }
+}
+
+impl From<u16> for PatchId {
+    fn from(value: u16) -> Self {
+        Self(value)
+    }
+}
+
+impl From<PatchId> for u16 {
+    fn from(value: PatchId) -> Self {
+        value.0
+    }
+}
+
+impl Display for PkgId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04x}", self.0)
+    }
+}
+
+impl std::fmt::LowerHex for PkgId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        std::fmt::LowerHex::fmt(&self.0, f)
+    }
+}
+
+impl Display for PatchId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
 impl Default for TagHash {
     fn default() -> Self {
         Self::NONE
@@ -47,7 +108,10 @@ impl Default for TagHash {
 impl TagHash {
     pub const NONE: TagHash = TagHash(u32::MAX);
 
-    pub fn new(pkg_id: u16, entry: u16) -> TagHash {
+    /// Creates a tag from a package ID and entry index, see [TagHash] for the layout.
+    /// Out of range values are silently truncated, use [TagHash::try_new] to catch those
+    pub fn new(pkg_id: impl Into<PkgId>, entry: u16) -> TagHash {
+        let PkgId(pkg_id) = pkg_id.into();
         TagHash(
             0x80800000u32
                 .wrapping_add((pkg_id as u32) << 13)
@@ -55,6 +119,22 @@ impl TagHash {
         )
     }
 
+    /// Creates a tag from a package ID and entry index.
+    /// Returns `None` if the entry index is out of range or the resulting hash is not valid
+    pub fn try_new(pkg_id: impl Into<PkgId>, entry: u16) -> Option<TagHash> {
+        let PkgId(pkg_id) = pkg_id.into();
+        if entry >= 8192 {
+            return None;
+        }
+
+        let tag = Self::new(pkg_id, entry);
+        if tag.is_valid() && tag.pkg_id() == pkg_id {
+            Some(tag)
+        } else {
+            None
+        }
+    }
+
     pub fn is_valid(&self) -> bool {
         self.0 > 0x80800000 && self.0 <= 0x81ffffff
     }
@@ -79,6 +159,16 @@ impl TagHash {
     pub fn entry_index(&self) -> u16 {
         ((self.0 & 0x1fff) % 8192) as u16
     }
+
+    /// Creates a tag from the components of its `pkg/entry` notation, see [TagHash::to_pkg_entry_string]
+    pub fn from_pkg_entry(pkg_id: u16, entry: u16) -> TagHash {
+        Self::new(pkg_id, entry)
+    }
+
+    /// Formats the tag as `{pkg_id:04x}/{entry_index}`, eg. `0a3f/1234`
+    pub fn to_pkg_entry_string(&self) -> String {
+        format!("{:04x}/{}", self.pkg_id(), self.entry_index())
+    }
 }
 
 impl Debug for TagHash {
@@ -163,3 +253,55 @@ impl Hash for TagHash64 {
         state.write_u64(self.0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use clap::ValueEnum;
+
+    use super::*;
+    use crate::GameVersion;
+
+    #[test]
+    fn tag_layout() {
+        let tag = TagHash::new(0x123, 5);
+        assert_eq!(tag, TagHash(0x80a46005));
+        assert_eq!(tag.pkg_id(), 0x123);
+        assert_eq!(tag.entry_index(), 5);
+        assert_eq!(TagHash::try_new(0x123, 8192), None);
+    }
+
+    /// Bits used for the entry index by each version. Deliberately exhaustive, so a new version has to state its layout
+    fn entry_index_bits(version: GameVersion) -> u32 {
+        match version {
+            GameVersion::DestinyInternalAlpha
+            | GameVersion::DestinyTheTakenKing
+            | GameVersion::DestinyRiseOfIron
+            | GameVersion::Destiny2Beta
+            | GameVersion::Destiny2Forsaken
+            | GameVersion::Destiny2Shadowkeep
+            | GameVersion::Destiny2BeyondLight
+            | GameVersion::Destiny2WitchQueen
+            | GameVersion::Destiny2Lightfall
+            | GameVersion::Destiny2TheFinalShape
+            | GameVersion::Destiny2Episodes => 13,
+        }
+    }
+
+    #[test]
+    fn tag_layout_per_version() {
+        for &version in GameVersion::value_variants() {
+            let bits = entry_index_bits(version);
+            for (pkg_id, entry) in [(0x001, 0), (0x123, 5), (0x3ff, (1 << bits) - 1)] {
+                let tag = TagHash::new(pkg_id, entry);
+                assert_eq!(
+                    tag.0,
+                    0x80800000 + ((pkg_id as u32) << bits) + entry as u32,
+                    "{version:?} {pkg_id:04x}/{entry}"
+                );
+                assert_eq!(tag.pkg_id(), pkg_id, "{version:?}");
+                assert_eq!(tag.entry_index(), entry, "{version:?}");
+                assert_eq!(TagHash::try_new(pkg_id, entry), Some(tag), "{version:?}");
+            }
+        }
+    }
+}
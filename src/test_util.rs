@@ -0,0 +1,94 @@
+//! Helpers for writing synthetic packages in tests
+
+use std::{collections::HashMap, fs, io::Cursor, path::Path, sync::Arc};
+
+use anyhow::ensure;
+
+use crate::{
+    package::PackagePlatform,
+    source::{PackageSource, SourceReader},
+    GameVersion, Package, PackageOpenOptions, PackageWriter,
+};
+
+/// Version that packages written by these helpers are built for
+pub const TEST_VERSION: GameVersion = GameVersion::Destiny2WitchQueen;
+
+/// Writer for an unencrypted, uncompressed package, so tests don't depend on Oodle being present
+pub fn writer(pkg_id: u16) -> PackageWriter {
+    let mut writer = PackageWriter::new(TEST_VERSION, PackagePlatform::Windows, pkg_id).unwrap();
+    writer.compress = false;
+    writer
+}
+
+/// Builds a package with one entry for every `(reference, data)` pair
+pub fn package_bytes(pkg_id: u16, entries: &[(u32, &[u8])]) -> Vec<u8> {
+    let mut writer = writer(pkg_id);
+    for (reference, data) in entries {
+        writer.add_entry(*reference, 8, 0, data.to_vec()).unwrap();
+    }
+
+    write(&writer)
+}
+
+pub fn write(writer: &PackageWriter) -> Vec<u8> {
+    let mut data = vec![];
+    writer.write(&mut data).unwrap();
+    data
+}
+
+/// Writes a package built by [package_bytes] to `dir/filename`
+pub fn write_package(dir: &Path, filename: &str, pkg_id: u16, entries: &[(u32, &[u8])]) {
+    fs::write(dir.join(filename), package_bytes(pkg_id, entries)).unwrap();
+}
+
+/// Serves a single package file from memory
+#[derive(Debug)]
+pub struct MemorySource(pub Vec<u8>);
+
+impl MemorySource {
+    pub const PATH: &'static str = "w64_test_0123_0.pkg";
+}
+
+impl PackageSource for MemorySource {
+    fn list_packages(&self) -> anyhow::Result<Vec<String>> {
+        Ok(vec![Self::PATH.to_string()])
+    }
+
+    fn open(&self, path: &str) -> anyhow::Result<SourceReader> {
+        ensure!(path == Self::PATH, "File {path} not found");
+        Ok(Box::new(Cursor::new(self.0.clone())))
+    }
+}
+
+/// Serves any number of files from memory, keyed by path
+#[derive(Debug, Default)]
+pub struct MemoryFiles(pub HashMap<String, Vec<u8>>);
+
+impl PackageSource for MemoryFiles {
+    fn list_packages(&self) -> anyhow::Result<Vec<String>> {
+        Ok(self.0.keys().cloned().collect())
+    }
+
+    fn open(&self, path: &str) -> anyhow::Result<SourceReader> {
+        let data = self
+            .0
+            .get(path)
+            .ok_or_else(|| anyhow::anyhow!("File {path} not found"))?;
+        Ok(Box::new(Cursor::new(data.clone())))
+    }
+}
+
+/// Opens a package straight from its bytes
+pub fn open_bytes(data: Vec<u8>) -> Arc<dyn Package> {
+    open_bytes_as(TEST_VERSION, data).unwrap()
+}
+
+/// Same as [open_bytes], but opens the package as the given version and returns any error
+pub fn open_bytes_as(version: GameVersion, data: Vec<u8>) -> anyhow::Result<Arc<dyn Package>> {
+    let options = PackageOpenOptions {
+        source: Some(Arc::new(MemorySource(data))),
+        ..Default::default()
+    };
+
+    version.open_with_options(MemorySource::PATH, &options)
+}
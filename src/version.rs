@@ -0,0 +1,220 @@
+#[cfg(feature = "reader")]
+use std::io::SeekFrom;
+use std::{
+    fs::{self, File},
+    io::Read,
+    path::Path,
+};
+
+use anyhow::{ensure, Context};
+#[cfg(feature = "reader")]
+use binrw::BinReaderExt;
+use itertools::Itertools;
+use tracing::warn;
+
+#[cfg(feature = "reader")]
+use crate::{error::PackageError, package::ReadSeek};
+use crate::{package::PackagePlatform, GameVersion};
+
+/// Maximum amount of packages that get their header read by [probe_directory]
+const PROBE_SAMPLE_SIZE: usize = 16;
+
+/// Header version used by all Destiny 2 packages from Beyond Light onwards
+pub(crate) const HEADER_VERSION_BL: u16 = 53;
+/// Header version used by Destiny 2 packages before Beyond Light (including the beta)
+pub(crate) const HEADER_VERSION_PREBL: u16 = 38;
+
+/// Returns every version that could have produced a package with the given header.
+/// Only the first 2 bytes are needed, the rest of the package is never touched
+pub fn probe_header(header: &[u8]) -> Vec<GameVersion> {
+    let Some(&[b0, b1]) = header.get(..2) else {
+        return vec![];
+    };
+
+    match u16::from_le_bytes([b0, b1]) {
+        HEADER_VERSION_BL => vec![
+            GameVersion::Destiny2BeyondLight,
+            GameVersion::Destiny2WitchQueen,
+            GameVersion::Destiny2Lightfall,
+            GameVersion::Destiny2TheFinalShape,
+            GameVersion::Destiny2Episodes,
+        ],
+        HEADER_VERSION_PREBL => vec![
+            GameVersion::Destiny2Beta,
+            GameVersion::Destiny2Forsaken,
+            GameVersion::Destiny2Shadowkeep,
+        ],
+        // Destiny 1 headers start with a small version number, so the byte order gives away the platform.
+        // Big endian packages come from the PS3/X360 builds
+        _ if b0 == 0 && b1 != 0 => vec![
+            GameVersion::DestinyInternalAlpha,
+            GameVersion::DestinyTheTakenKing,
+        ],
+        _ if b0 != 0 && b1 == 0 => vec![GameVersion::DestinyRiseOfIron],
+        _ => vec![],
+    }
+}
+
+/// Checks the version word at the start of a package header, returning [PackageError::VersionMismatch] if it doesn't
+/// match. The reader is rewound to the start of the package afterwards
+#[cfg(feature = "reader")]
+pub(crate) fn check_header_version(
+    reader: &mut impl ReadSeek,
+    expected: u16,
+) -> anyhow::Result<()> {
+    reader.seek(SeekFrom::Start(0))?;
+    let found: u16 = reader.read_le()?;
+    reader.seek(SeekFrom::Start(0))?;
+
+    if found != expected {
+        return Err(PackageError::VersionMismatch { expected, found }.into());
+    }
+
+    Ok(())
+}
+
+/// Reads the header of a single package and returns every version it's compatible with
+pub fn probe_file(path: &Path) -> anyhow::Result<Vec<GameVersion>> {
+    let mut header = [0u8; 2];
+    File::open(path)
+        .and_then(|mut f| f.read_exact(&mut header))
+        .with_context(|| format!("Failed to read package header from {}", path.display()))?;
+
+    Ok(probe_header(&header))
+}
+
+/// Reads the platform ID stored right after the header version, without parsing the rest of the package.
+/// Works for every supported package format, the byte order is derived from the header version
+pub fn peek_platform(path: &str) -> anyhow::Result<PackagePlatform> {
+    let mut header = [0u8; 4];
+    File::open(path)
+        .and_then(|mut f| f.read_exact(&mut header))
+        .with_context(|| format!("Failed to read package header from {path}"))?;
+
+    platform_from_header(&header, path)
+}
+
+/// Decodes the platform ID from the first 4 bytes of a package header, see [peek_platform].
+/// `path` is only used in error messages
+pub(crate) fn platform_from_header(
+    header: &[u8; 4],
+    path: &str,
+) -> anyhow::Result<PackagePlatform> {
+    ensure!(
+        !probe_header(header).is_empty(),
+        "Unrecognized package header in {path}"
+    );
+
+    // Same check as probe_header, big endian headers have their version in the second byte
+    let platform_id = if header[0] == 0 && header[1] != 0 {
+        u16::from_be_bytes([header[2], header[3]])
+    } else {
+        u16::from_le_bytes([header[2], header[3]])
+    };
+
+    Ok(match platform_id {
+        2 | 6 => PackagePlatform::Windows,
+        3 => PackagePlatform::X360,
+        4 => PackagePlatform::PS3,
+        7 => PackagePlatform::PS4,
+        8 => PackagePlatform::XboxOne,
+        u => anyhow::bail!("Unsupported platform ID {u} in {path}"),
+    })
+}
+
+/// Platform ID that Destiny 2 package headers store for the given platform, the inverse of [platform_from_header].
+/// Returns `None` for platforms Destiny 2 was never released on
+#[cfg(feature = "reader")]
+pub(crate) fn d2_header_platform_id(platform: PackagePlatform) -> Option<u16> {
+    match platform {
+        PackagePlatform::Windows => Some(2),
+        PackagePlatform::PS4 => Some(7),
+        PackagePlatform::XboxOne => Some(8),
+        PackagePlatform::PS3 | PackagePlatform::X360 => None,
+    }
+}
+
+/// Reads the headers of a sample of packages in the given directory and returns the versions compatible with all of
+/// them, newest first. Headers alone can't tell every version apart, so this may return more than one version
+pub fn probe_directory(dir: &Path) -> anyhow::Result<Vec<GameVersion>> {
+    let packages = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read directory {}", dir.display()))?
+        .filter_map(Result::ok)
+        .map(|e| e.path())
+        .filter(|p| p.is_file() && p.to_string_lossy().to_lowercase().ends_with(".pkg"))
+        .sorted()
+        .take(PROBE_SAMPLE_SIZE)
+        .collect_vec();
+
+    ensure!(
+        !packages.is_empty(),
+        "No packages found in {}",
+        dir.display()
+    );
+
+    let mut candidates: Option<Vec<GameVersion>> = None;
+    for path in &packages {
+        let versions = match probe_file(path) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("{e:?}");
+                continue;
+            }
+        };
+
+        candidates = Some(match candidates {
+            Some(c) => c.into_iter().filter(|v| versions.contains(v)).collect(),
+            None => versions,
+        });
+    }
+
+    let mut candidates = candidates.unwrap_or_default();
+    candidates.sort_by(|a, b| b.cmp(a));
+
+    Ok(candidates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "reader")]
+    use crate::{error::PackageError, test_util};
+
+    #[test]
+    fn probe_directory_lists_newest_first() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("w64_test_0123_0.pkg"),
+            HEADER_VERSION_PREBL.to_le_bytes(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            probe_directory(dir.path()).unwrap(),
+            [
+                GameVersion::Destiny2Shadowkeep,
+                GameVersion::Destiny2Forsaken,
+                GameVersion::Destiny2Beta
+            ]
+        );
+    }
+
+    #[cfg(feature = "reader")]
+    #[test]
+    fn prebl_header_opened_as_beyondlight() {
+        let mut header = vec![0u8; 0x200];
+        header[0..2].copy_from_slice(&HEADER_VERSION_PREBL.to_le_bytes());
+        header[2..4].copy_from_slice(&2u16.to_le_bytes());
+
+        let err = test_util::open_bytes_as(GameVersion::Destiny2BeyondLight, header)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.downcast_ref::<PackageError>(),
+            Some(&PackageError::VersionMismatch {
+                expected: HEADER_VERSION_BL,
+                found: HEADER_VERSION_PREBL
+            })
+        );
+    }
+}
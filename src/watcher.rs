@@ -0,0 +1,189 @@
+use std::{fs, path::Path, sync::Arc, time::Duration};
+
+use notify_debouncer_mini::{
+    new_debouncer,
+    notify::{RecommendedWatcher, RecursiveMode},
+    DebounceEventResult, Debouncer,
+};
+use rustc_hash::FxHashSet;
+use tracing::{error, info};
+
+use crate::{manager::PackagePath, PackageManager};
+
+/// Games usually write multiple patch files in quick succession during an update,
+/// so events are collected for this long before packages get re-indexed
+const DEBOUNCE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackageChange {
+    /// A package that wasn't registered before has been added
+    Added(u16),
+    /// A new patch was added for a package, or the package file itself was changed
+    Modified(u16),
+    /// All files for a package have been removed
+    Removed(u16),
+}
+
+/// Keeps the directory watcher alive, dropping this will stop watching for changes
+pub struct PackageWatcher {
+    _debouncer: Debouncer<RecommendedWatcher>,
+}
+
+impl PackageManager {
+    /// Watches the packages directory for changes, re-indexing packages as they get added, removed or modified.
+    pub fn watch(
+        self: Arc<Self>,
+        on_change: impl Fn(PackageChange) + Send + 'static,
+    ) -> anyhow::Result<PackageWatcher> {
+        let package_dirs = std::iter::once(self.package_dir.clone())
+            .chain(self.extra_package_dirs.iter().cloned())
+            .collect::<Vec<_>>();
+
+        let mut debouncer = new_debouncer(DEBOUNCE_TIMEOUT, move |res: DebounceEventResult| {
+            let events = match res {
+                Ok(events) => events,
+                Err(e) => {
+                    error!("Package directory watch error: {e}");
+                    return;
+                }
+            };
+
+            let changed_packages: FxHashSet<u16> = events
+                .iter()
+                .filter(|e| e.path.to_string_lossy().to_lowercase().ends_with(".pkg"))
+                .filter_map(|e| PackagePath::parse(&e.path.to_string_lossy()))
+                .filter_map(|p| p.numeric_id())
+                .collect();
+
+            for pkg_id in changed_packages {
+                let change = self.apply_package_change(pkg_id);
+                match change {
+                    Ok(Some(change)) => on_change(change),
+                    Ok(None) => {}
+                    Err(e) => error!("Failed to re-index package {pkg_id:04x}: {e}"),
+                }
+            }
+        })?;
+
+        for package_dir in &package_dirs {
+            debouncer
+                .watcher()
+                .watch(package_dir, RecursiveMode::NonRecursive)?;
+
+            info!("Watching {} for package changes", package_dir.display());
+        }
+
+        Ok(PackageWatcher {
+            _debouncer: debouncer,
+        })
+    }
+
+    fn apply_package_change(&self, pkg_id: u16) -> anyhow::Result<Option<PackageChange>> {
+        // Later directories take priority, same as during package discovery
+        let mut latest = None;
+        for dir in std::iter::once(&self.package_dir).chain(&self.extra_package_dirs) {
+            if let Some(p) = find_latest_patch(dir, pkg_id)? {
+                latest = Some(p);
+            }
+        }
+
+        let was_registered = self.package_paths.read().contains_key(&pkg_id);
+
+        match latest {
+            Some(path) => {
+                self.package_paths.write().insert(pkg_id, path);
+                self.index_package(pkg_id)?;
+                Ok(Some(if was_registered {
+                    PackageChange::Modified(pkg_id)
+                } else {
+                    PackageChange::Added(pkg_id)
+                }))
+            }
+            None if was_registered => {
+                self.package_paths.write().remove(&pkg_id);
+                self.unindex_package(pkg_id);
+                Ok(Some(PackageChange::Removed(pkg_id)))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Finds the highest patch file for the given package ID, ignoring non-english language packages
+fn find_latest_patch(package_dir: &Path, pkg_id: u16) -> anyhow::Result<Option<PackagePath>> {
+    let mut latest: Option<PackagePath> = None;
+    for entry in fs::read_dir(package_dir)? {
+        let path = entry?.path();
+        if !path.is_file() || !path.to_string_lossy().to_lowercase().ends_with(".pkg") {
+            continue;
+        }
+
+        let Some(p) = PackagePath::parse(&path.to_string_lossy()) else {
+            continue;
+        };
+
+        if p.numeric_id() != Some(pkg_id) || !p.is_english() {
+            continue;
+        }
+
+        if latest.as_ref().is_none_or(|l| l.patch < p.patch) {
+            latest = Some(p);
+        }
+    }
+
+    Ok(latest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, TEST_VERSION};
+
+    #[test]
+    fn package_changes_update_the_index() {
+        let dir = tempfile::tempdir().unwrap();
+        test_util::write_package(
+            dir.path(),
+            "w64_test_0123_0.pkg",
+            0x123,
+            &[(0x80800000, b"a")],
+        );
+        let manager = PackageManager::builder(dir.path(), TEST_VERSION)
+            .cache_dir(dir.path().join("cache"))
+            .build()
+            .unwrap();
+
+        let tag = crate::TagHash::new(0x124, 0);
+        test_util::write_package(
+            dir.path(),
+            "w64_test_0124_0.pkg",
+            0x124,
+            &[(0x80800000, b"b")],
+        );
+        assert_eq!(
+            manager.apply_package_change(0x124).unwrap(),
+            Some(PackageChange::Added(0x124))
+        );
+        assert_eq!(manager.read_tag(tag).unwrap(), b"b");
+
+        test_util::write_package(
+            dir.path(),
+            "w64_test_0124_1.pkg",
+            0x124,
+            &[(0x80800000, b"c")],
+        );
+        assert_eq!(
+            manager.apply_package_change(0x124).unwrap(),
+            Some(PackageChange::Modified(0x124))
+        );
+        assert_eq!(manager.read_tag(tag).unwrap(), b"c");
+
+        fs::remove_file(dir.path().join("w64_test_0124_0.pkg")).unwrap();
+        fs::remove_file(dir.path().join("w64_test_0124_1.pkg")).unwrap();
+        assert_eq!(
+            manager.apply_package_change(0x124).unwrap(),
+            Some(PackageChange::Removed(0x124))
+        );
+        assert!(manager.get_entry(tag).is_none());
+    }
+}